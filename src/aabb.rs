@@ -6,7 +6,7 @@
 //! While we should be using more than i16 for chunk coordinates, this is for minigame servers and we are fine
 //! using it as we are optimizing for performance
 use crate::Point;
-use glam::U16Vec2;
+use glam::UVec2;
 use more_asserts::debug_assert_le;
 use std::fmt::{Debug, Formatter};
 
@@ -76,27 +76,81 @@ impl Aabb {
             && self.max.y >= other.min.y
     }
 
+    /// The per-axis lengths to the closest and furthest possible point inside `self`.
     #[must_use]
-    pub fn min_max_distance2(self, point: glam::I16Vec2) -> (u32, u32) {
-        let this_lens = U16Vec2::from(self.lens());
-
-        let enclosing = self.enclose(point);
+    #[allow(clippy::cast_sign_loss)]
+    pub fn min_max_lens(self, point: glam::I16Vec2) -> (UVec2, UVec2) {
+        let point = point.as_ivec2();
+        let min = self.min.as_ivec2();
+        let max = self.max.as_ivec2();
 
-        let exterior_lens = U16Vec2::from(enclosing.lens());
-        let enclosing_lens = exterior_lens - this_lens;
+        let closest = point.clamp(min, max);
+        let enclosing_lens = (point - closest).abs().as_uvec2();
 
-        let exterior_lens = exterior_lens.as_uvec2();
-        let enclosing_lens = enclosing_lens.as_uvec2();
+        let exterior_lens = (point - min).abs().max((point - max).abs()).as_uvec2();
 
         debug_assert_le!(exterior_lens.x, u32::from(u16::MAX));
         debug_assert_le!(exterior_lens.y, u32::from(u16::MAX));
 
+        (enclosing_lens, exterior_lens)
+    }
+
+    /// The per-axis lengths between the closest points of `self` and `other`.
+    #[must_use]
+    #[allow(clippy::cast_sign_loss)]
+    pub fn gap_lens(self, other: Self) -> UVec2 {
+        let gap = (self.min.as_ivec2() - other.max.as_ivec2())
+            .max(other.min.as_ivec2() - self.max.as_ivec2())
+            .max(glam::IVec2::ZERO);
+
+        gap.as_uvec2()
+    }
+
+    #[must_use]
+    pub fn min_max_distance2(self, point: glam::I16Vec2) -> (u32, u32) {
+        let (enclosing_lens, exterior_lens) = self.min_max_lens(point);
+
         let min_dist2 = enclosing_lens.length_squared();
         let max_dist2 = exterior_lens.length_squared();
 
         (min_dist2, max_dist2)
     }
 
+    /// Where the ray `origin + t * direction` enters the cells covered by `self`.
+    ///
+    /// Every point is treated as the unit cell `point..point + 1`, so `self` spans `min..max + 1`.
+    /// Returns `None` if the ray misses or only enters after `max_t`.
+    #[must_use]
+    pub fn ray_entry(self, origin: glam::Vec2, direction: glam::Vec2, max_t: f32) -> Option<f32> {
+        let min = self.min.as_vec2();
+        let max = self.max.as_vec2() + 1.0;
+
+        let mut entry = 0.0_f32;
+        let mut exit = max_t;
+
+        for axis in 0..2 {
+            let origin = origin[axis];
+            let direction = direction[axis];
+
+            if direction == 0.0 {
+                // parallel to the slab
+                if origin < min[axis] || origin >= max[axis] {
+                    return None;
+                }
+
+                continue;
+            }
+
+            let t1 = (min[axis] - origin) / direction;
+            let t2 = (max[axis] - origin) / direction;
+
+            entry = entry.max(t1.min(t2));
+            exit = exit.min(t1.max(t2));
+        }
+
+        (entry <= exit).then_some(entry)
+    }
+
     pub fn enclosing_aabb<I: Point>(elems: impl IntoIterator<Item = I>) -> Self {
         let elems = elems.into_iter();
         // 16 bits
@@ -129,12 +183,34 @@ impl Aabb {
         let ly = self.max.y.abs_diff(self.min.y);
         [lx, ly]
     }
+
+    /// The sum of both side lengths, used to compare the size of boxes.
+    #[must_use]
+    pub const fn half_perimeter(self) -> u32 {
+        let [lx, ly] = self.lens();
+        lx as u32 + ly as u32
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_min_max_lens() {
+        let aabb = Aabb::new(glam::I16Vec2::new(0, 0), glam::I16Vec2::new(10, 10));
+
+        // outside: the closest point is on the left edge, the furthest in a right corner
+        let (min, max) = aabb.min_max_lens(glam::I16Vec2::new(-3, 5));
+        assert_eq!(min, UVec2::new(3, 0));
+        assert_eq!(max, UVec2::new(13, 5));
+
+        // inside: every corner is 5 away on each axis, not the whole length of the box
+        let (min, max) = aabb.min_max_lens(glam::I16Vec2::new(5, 5));
+        assert_eq!(min, UVec2::ZERO);
+        assert_eq!(max, UVec2::new(5, 5));
+    }
+
     #[test]
     fn test_lens() {
         let aabb = Aabb::new(glam::I16Vec2::new(0, 0), glam::I16Vec2::new(10, 10));
@@ -164,6 +240,43 @@ mod tests {
         assert_eq!(lens[1], 20);
     }
 
+    #[test]
+    fn test_ray_entry() {
+        let aabb = Aabb::new(glam::I16Vec2::new(2, 0), glam::I16Vec2::new(3, 1));
+
+        let origin = glam::Vec2::new(0.5, 0.5);
+
+        assert_eq!(aabb.ray_entry(origin, glam::Vec2::X, 10.0), Some(1.5));
+        assert_eq!(aabb.ray_entry(origin, glam::Vec2::X, 1.0), None);
+        assert_eq!(aabb.ray_entry(origin, glam::Vec2::NEG_X, 10.0), None);
+        assert_eq!(aabb.ray_entry(origin, glam::Vec2::Y, 10.0), None);
+
+        // starting inside
+        let origin = glam::Vec2::new(2.5, 1.5);
+        assert_eq!(aabb.ray_entry(origin, glam::Vec2::ONE, 10.0), Some(0.0));
+
+        // diagonal that only clips the corner cell
+        let origin = glam::Vec2::new(0.0, 3.0);
+        let direction = glam::Vec2::new(1.0, -1.0);
+        assert_eq!(aabb.ray_entry(origin, direction, 10.0), Some(2.0));
+    }
+
+    #[test]
+    fn test_gap_lens() {
+        let a = Aabb::new(glam::I16Vec2::new(0, 0), glam::I16Vec2::new(2, 2));
+
+        let b = Aabb::new(glam::I16Vec2::new(5, 1), glam::I16Vec2::new(6, 8));
+        assert_eq!(a.gap_lens(b), UVec2::new(3, 0));
+        assert_eq!(b.gap_lens(a), UVec2::new(3, 0));
+
+        let c = Aabb::point(glam::I16Vec2::new(-2, -4));
+        assert_eq!(a.gap_lens(c), UVec2::new(2, 4));
+
+        // overlapping
+        let d = Aabb::new(glam::I16Vec2::new(1, 1), glam::I16Vec2::new(3, 3));
+        assert_eq!(a.gap_lens(d), UVec2::ZERO);
+    }
+
     #[test]
     fn test_lens_zero() {
         let aabb = Aabb::new(glam::I16Vec2::new(0, 0), glam::I16Vec2::new(0, 0));
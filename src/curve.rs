@@ -0,0 +1,103 @@
+//! Space-filling curves that decide the order of leaves.
+//!
+//! The tree is built by sorting the input by the key of its point and splitting the sorted list
+//! in halves, so points that are close on the curve share subtrees and have adjacent ranges. The
+//! curve only affects how tight the nodes are and how well ranges merge; queries are correct with
+//! any curve.
+use glam::I16Vec2;
+
+use crate::add_half_max_and_convert;
+
+pub trait Curve: Copy {
+    /// The position of `point` along the curve.
+    fn key(self, point: I16Vec2) -> u32;
+}
+
+/// The Hilbert curve, which keeps consecutive keys next to each other.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Hilbert;
+
+impl Curve for Hilbert {
+    fn key(self, point: I16Vec2) -> u32 {
+        let x = add_half_max_and_convert(point.x);
+        let y = add_half_max_and_convert(point.y);
+        fast_hilbert::xy2h(x, y, 32)
+    }
+}
+
+/// The Z-order curve, which interleaves the bits of both coordinates.
+///
+/// Cheaper to compute than [`Hilbert`], at the cost of occasional jumps between quadrants.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Morton;
+
+impl Curve for Morton {
+    fn key(self, point: I16Vec2) -> u32 {
+        /// Spreads the bits of `x` to the even bits of the result.
+        fn spread(x: u16) -> u32 {
+            let mut x = u32::from(x);
+            x = (x | (x << 8)) & 0x00FF_00FF;
+            x = (x | (x << 4)) & 0x0F0F_0F0F;
+            x = (x | (x << 2)) & 0x3333_3333;
+            x = (x | (x << 1)) & 0x5555_5555;
+            x
+        }
+
+        let x = add_half_max_and_convert(point.x);
+        let y = add_half_max_and_convert(point.y);
+        spread(x) | (spread(y) << 1)
+    }
+}
+
+/// Rows of increasing `y`, each ordered by `x`.
+///
+/// Works well for long, thin maps that stretch along `x`.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct RowMajor;
+
+impl Curve for RowMajor {
+    fn key(self, point: I16Vec2) -> u32 {
+        let x = add_half_max_and_convert(point.x);
+        let y = add_half_max_and_convert(point.y);
+        (u32::from(y) << 16) | u32::from(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_morton_key() {
+        let origin = I16Vec2::splat(i16::MIN);
+
+        assert_eq!(Morton.key(origin), 0);
+        assert_eq!(Morton.key(origin + I16Vec2::new(1, 0)), 0b01);
+        assert_eq!(Morton.key(origin + I16Vec2::new(0, 1)), 0b10);
+        assert_eq!(Morton.key(origin + I16Vec2::new(3, 1)), 0b0111);
+        assert_eq!(Morton.key(origin + I16Vec2::new(2, 2)), 0b1100);
+        assert_eq!(Morton.key(I16Vec2::splat(i16::MAX)), u32::MAX);
+    }
+
+    #[test]
+    fn test_row_major_key() {
+        let mut points = vec![
+            I16Vec2::new(5, -1),
+            I16Vec2::new(-5, 0),
+            I16Vec2::new(-10, -1),
+            I16Vec2::new(3, 0),
+        ];
+
+        points.sort_by_key(|&point| RowMajor.key(point));
+
+        assert_eq!(
+            points,
+            [
+                I16Vec2::new(-10, -1),
+                I16Vec2::new(5, -1),
+                I16Vec2::new(-5, 0),
+                I16Vec2::new(3, 0),
+            ]
+        );
+    }
+}
@@ -5,25 +5,57 @@
 #![feature(associated_type_defaults)]
 
 pub use crate::aabb::Aabb;
+pub use crate::curve::{Curve, Hilbert, Morton, RowMajor};
+pub use crate::metric::{Chebyshev, Euclidean, Manhattan, Metric};
 use crate::node::{Expanded, Leaf, LeafPtr, Node};
+pub use crate::query::{write_all_vectored, Budgeted, ClosestApprox, Framing, InDiff, RayHit};
 use crate::sealed::PointWithData;
+pub use crate::shape::{Circle, ConvexPolygon, Difference, QueryShape, ViewWedge};
 use more_asserts::debug_assert_lt;
 use std::alloc::{Allocator, Global};
 use std::cell::Cell;
 use std::fmt::{Debug, Formatter};
 use std::num::NonZeroU32;
+use std::ops::Range;
+#[cfg(feature = "stats")]
+use std::sync::atomic::{AtomicU64, Ordering};
 
 mod aabb;
+mod curve;
+mod metric;
 
 pub mod node;
 mod print;
 
 mod query;
+mod rebuild;
+mod refit;
+mod sah;
+mod shape;
+mod sort;
 
 pub struct Bvh<L, A: Allocator = Global> {
     nodes: Box<[Cell<Node>], A>,
     data: L,
     leaves: Vec<Leaf, A>,
+    /// The sum of the half perimeters of all inner nodes, see [`Bvh::cost`]. `None` until it
+    /// is first needed, so builds do not pay for it.
+    cost: Cell<Option<u64>>,
+    /// The length of the input the tree was built from, see [`Bvh::rebuild`].
+    input_len: u32,
+    /// Where the children of inner nodes are, see [`Bvh::left_child`].
+    layout: Layout<A>,
+    /// The number of node reads by queries, see [`Bvh::nodes_visited`].
+    #[cfg(feature = "stats")]
+    nodes_visited: AtomicU64,
+}
+
+/// How the children of an inner node are found.
+enum Layout<A: Allocator> {
+    /// The children of `i` are `2i` and `2i + 1`.
+    Implicit,
+    /// The `[left, right]` children of every inner node, from [`Bvh::build_sah_in`].
+    Explicit(Box<[[u32; 2]], A>),
 }
 
 impl <L, A: Allocator> Bvh<L, A> {
@@ -53,6 +85,11 @@ impl<L: Default, A: Allocator + Default> Default for Bvh<L, A> {
             nodes: Box::new_in([], A::default()),
             data: L::default(),
             leaves: Vec::with_capacity_in(0, A::default()),
+            cost: Cell::new(Some(0)),
+            input_len: 0,
+            layout: Layout::Implicit,
+            #[cfg(feature = "stats")]
+            nodes_visited: AtomicU64::new(0),
         }
     }
 }
@@ -112,6 +149,17 @@ impl<T> Bvh<Vec<T>> {
     {
         Self::build_in(input, Global, context)
     }
+
+    /// [`Bvh::build`] with the leaves ordered along `curve` instead of the Hilbert curve.
+    #[must_use]
+    pub fn build_with_curve<I, C>(input: &mut [I], curve: C, context: I::Context<'_>) -> Self
+    where
+        I: PointWithData<Unit = T>,
+        T: Copy + 'static,
+        C: Curve,
+    {
+        Self::build_in_with_curve(input, Global, curve, context)
+    }
 }
 
 #[allow(clippy::non_send_fields_in_send_ty)]
@@ -120,142 +168,288 @@ unsafe impl<T: Sync, A: Allocator> Sync for Bvh<T, A> {}
 
 impl<T, A: Allocator + Clone> Bvh<Vec<T, A>, A> {
     #[must_use]
-    #[allow(clippy::cast_possible_truncation)]
-    #[allow(clippy::too_many_lines)]
     pub fn build_in<I>(input: &mut [I], alloc: A, context: I::Context<'_>) -> Self
     where
         I: PointWithData<Unit = T>,
         T: Copy + 'static,
+    {
+        Self::build_in_with_curve(input, alloc, Hilbert, context)
+    }
+
+    /// [`Bvh::build_in`] with the leaves ordered along `curve` instead of the Hilbert curve.
+    ///
+    /// `input` is left sorted by [`Curve::key`].
+    #[must_use]
+    pub fn build_in_with_curve<I, C>(
+        input: &mut [I],
+        alloc: A,
+        curve: C,
+        context: I::Context<'_>,
+    ) -> Self
+    where
+        I: PointWithData<Unit = T>,
+        T: Copy + 'static,
+        C: Curve,
     {
         if input.is_empty() {
-            return Self {
-                nodes: Box::new_in([], alloc.clone()),
-                data: Vec::new_in(alloc.clone()),
-                leaves: Vec::new_in(alloc),
-            };
+            return Self::empty_in(alloc);
         }
 
-        input.sort_by_cached_key(|x| {
-            let point = x.point();
-            let x = add_half_max_and_convert(point.x);
-            let y = add_half_max_and_convert(point.y);
-            fast_hilbert::xy2h(x, y, 32)
-        });
+        sort::sort_by_curve(input, curve, alloc.clone());
+
+        let (data, leaves, points) = process_input(input, alloc.clone(), context);
+
+        let mut nodes = leaf_nodes(&points, alloc);
+        build_levels(&mut nodes, leaves.len());
+
+        Self::from_parts(nodes, data, leaves, input.len())
+    }
 
-        let (data, mut leaves, points) = process_input(input, alloc.clone(), context);
+    fn empty_in(alloc: A) -> Self {
+        Self {
+            nodes: Box::new_in([], alloc.clone()),
+            data: Vec::new_in(alloc.clone()),
+            leaves: Vec::new_in(alloc),
+            cost: Cell::new(Some(0)),
+            input_len: 0,
+            layout: Layout::Implicit,
+            #[cfg(feature = "stats")]
+            nodes_visited: AtomicU64::new(0),
+        }
+    }
 
-        let leaves_next_pow2 = leaves.len().next_power_of_two();
-        let total_size = leaves_next_pow2 + leaves.len();
+    #[allow(clippy::cast_possible_truncation)]
+    fn from_parts(
+        nodes: Box<[Node], A>,
+        data: Vec<T, A>,
+        mut leaves: Vec<Leaf, A>,
+        input_len: usize,
+    ) -> Self {
+        leaves.push(Leaf::new(data.len() as u32));
 
-        let mut nodes = unsafe { Box::new_zeroed_slice_in(total_size, alloc).assume_init() };
+        Self {
+            nodes: into_cells(nodes),
+            data,
+            leaves,
+            cost: Cell::new(None),
+            input_len: input_len as u32,
+            layout: Layout::Implicit,
+            #[cfg(feature = "stats")]
+            nodes_visited: AtomicU64::new(0),
+        }
+    }
+}
 
-        let mut root_set = false;
+#[cfg(feature = "parallel")]
+impl<T> Bvh<Vec<T>> {
+    /// [`Bvh::build`] on the rayon thread pool. The result is identical to [`Bvh::build`].
+    #[must_use]
+    pub fn par_build<'c, I>(input: &mut [I], context: I::Context<'c>) -> Self
+    where
+        I: PointWithData<Unit = T> + Send + Sync,
+        I::Context<'c>: Sync,
+        T: Copy + Send + Sync + 'static,
+    {
+        Self::par_build_in(input, Global, context)
+    }
+}
 
-        for (i, &point) in points.iter().enumerate() {
-            let leaf = Node::leaf(point, unsafe { u32::try_from(i).unwrap_unchecked() });
-            let leaf = Cell::new(leaf);
-            let idx = i + leaves_next_pow2;
+#[cfg(feature = "parallel")]
+impl<T, A: Allocator + Clone> Bvh<Vec<T, A>, A> {
+    /// [`Bvh::build_in`] on the rayon thread pool. The result is identical to [`Bvh::build_in`].
+    ///
+    /// Sorting, copying the data and every level of inner nodes are parallelised. Levels still
+    /// have to be built one after the other.
+    #[must_use]
+    pub fn par_build_in<'c, I>(input: &mut [I], alloc: A, context: I::Context<'c>) -> Self
+    where
+        I: PointWithData<Unit = T> + Send + Sync,
+        I::Context<'c>: Sync,
+        T: Copy + Send + Sync + 'static,
+    {
+        Self::par_build_in_with_curve(input, alloc, Hilbert, context)
+    }
 
-            if idx == 1 {
-                root_set = true;
-            }
+    /// [`Bvh::par_build_in`] with the leaves ordered along `curve`. The result is identical to
+    /// [`Bvh::build_in_with_curve`].
+    #[must_use]
+    pub fn par_build_in_with_curve<'c, I, C>(
+        input: &mut [I],
+        alloc: A,
+        curve: C,
+        context: I::Context<'c>,
+    ) -> Self
+    where
+        I: PointWithData<Unit = T> + Send + Sync,
+        I::Context<'c>: Sync,
+        T: Copy + Send + Sync + 'static,
+        C: Curve + Sync,
+    {
+        use rayon::prelude::*;
 
-            nodes[idx] = leaf;
+        if input.is_empty() {
+            return Self::empty_in(alloc);
         }
 
+        sort::par_sort_by_curve(input, curve);
+
+        let (data, leaves, points) = par_process_input(input, alloc.clone(), context);
+
+        let mut nodes = leaf_nodes(&points, alloc);
+        let leaves_next_pow2 = points.len().next_power_of_two();
+
         let mut current_level_start = leaves_next_pow2 / 2;
 
-        while current_level_start >= 1 {
-            for i in current_level_start..(current_level_start * 2) {
-                if i == 1 {
-                    root_set = true;
-                }
+        let leaves_len = leaves.len();
 
-                let i = i as u32;
-
-                let left = child_left(i) as usize;
-                let right = child_right(i) as usize;
-
-                let left = nodes.get(left).map(Cell::get).and_then(Node::into_expanded);
-                let right = nodes
-                    .get(right)
-                    .map(Cell::get)
-                    .and_then(Node::into_expanded);
-
-                let parent_node = match (left, right) {
-                    (Some(Expanded::Aabb(left)), Some(Expanded::Aabb(right))) => {
-                        let aabb = left.merge(right);
-                        Node::aabb(aabb)
-                    }
-                    (Some(Expanded::Aabb(left)), Some(Expanded::Leaf(right))) => {
-                        let aabb = left.enclose(right.point);
-                        Node::aabb(aabb)
-                    }
-                    (Some(Expanded::Aabb(left)), ..) => {
-                        // todo: try to restructure to eliminate this branch
-                        Node::aabb(left)
-                    }
-                    (Some(Expanded::Leaf(left)), Some(Expanded::Leaf(right))) // valid, valid
-                        if left.is_valid() && right.is_valid() =>
-                    {
-                        debug_assert!(left.point != right.point, "got {left:?} and {right:?}");
-                        let aabb = Aabb::enclosing_aabb([left.point, right.point]);
-                        Node::aabb(aabb)
-                    }
-                    (Some(Expanded::Leaf(left)), _) // valid, invalid
-                        if left.is_valid() =>
-                    {
-                        Node::from(left)
-                    }
-                    (left, right) => {
-                        #[cfg(debug_assertions)]
-                        {
-                            if let Some(left) = left {
-                                let Expanded::Leaf(left) = left else { unreachable!() };
-                                debug_assert!(left.is_invalid(), "expected invalid left leaf, got {left:?}");
-                            }
-
-                            if let Some(right) = right {
-                                let Expanded::Leaf(right) = right else { unreachable!() };
-                                debug_assert!(right.is_invalid(), "expected invalid right leaf, got {right:?}");
-                            }
-
-                        }
-                        Node::from(LeafPtr::INVALID)
-                    },
-                };
-
-                #[cfg(debug_assertions)]
-                {
-                    if let Some(Expanded::Leaf(leaf)) = parent_node.into_expanded() {
-                        debug_assert_lt!(
-                                leaf.ptr,
-                                leaves.len() as u32,
-                                "leaf.ptr {} is out of bounds for leaves.len() of {}, left leaf: {left:?}, right leaf: {right:?}",
-                                leaf.ptr,
-                                leaves.len()
-                        );
-                    }
-                }
+        while current_level_start >= 1 {
+            let (level, children) = level_and_children(&mut nodes, current_level_start);
 
-                nodes[i as usize] = Cell::new(parent_node);
-            }
+            level.par_iter_mut().enumerate().for_each(|(i, node)| {
+                *node = parent_node(children, i, leaves_len);
+            });
 
             current_level_start /= 2;
         }
 
-        debug_assert!(root_set);
+        Self::from_parts(nodes, data, leaves, input.len())
+    }
+}
 
-        leaves.push(Leaf::new(data.len() as u32));
+/// `nodes` as the cells [`Bvh`] stores them in.
+fn into_cells<A: Allocator>(nodes: Box<[Node], A>) -> Box<[Cell<Node>], A> {
+    // `Cell<Node>` has the same layout as `Node`
+    let (nodes, alloc) = Box::into_raw_with_allocator(nodes);
+    unsafe { Box::from_raw_in(nodes as *mut [Cell<Node>], alloc) }
+}
 
-        Self {
-            nodes,
-            data,
-            leaves,
+/// All nodes of a tree over `points`, where only the leaves are filled in.
+#[allow(clippy::cast_possible_truncation)]
+fn leaf_nodes<A: Allocator>(points: &[glam::I16Vec2], alloc: A) -> Box<[Node], A> {
+    let leaves_next_pow2 = points.len().next_power_of_two();
+    let total_size = leaves_next_pow2 + points.len();
+
+    // zeroed so everything is equivalent to Aabb with 0,0
+    let mut nodes = unsafe { Box::new_zeroed_slice_in(total_size, alloc).assume_init() };
+
+    for (i, &point) in points.iter().enumerate() {
+        nodes[i + leaves_next_pow2] = Node::leaf(point, i as u32);
+    }
+
+    nodes
+}
+
+/// Fills in the inner nodes of `nodes` from [`leaf_nodes`] bottom-up.
+fn build_levels(nodes: &mut [Node], leaves_len: usize) {
+    let leaves_next_pow2 = leaves_len.next_power_of_two();
+
+    let mut current_level_start = leaves_next_pow2 / 2;
+
+    while current_level_start >= 1 {
+        let (level, children) = level_and_children(nodes, current_level_start);
+
+        for (i, node) in level.iter_mut().enumerate() {
+            *node = parent_node(children, i, leaves_len);
         }
+
+        current_level_start /= 2;
     }
 }
 
+/// The half perimeter of `node` if it is an inner node.
+fn node_cost(node: Node) -> u64 {
+    match node.into_expanded() {
+        Some(Expanded::Aabb(aabb)) => u64::from(aabb.half_perimeter()),
+        _ => 0,
+    }
+}
+
+/// Splits `nodes` into the level starting at `level_start` and everything below it.
+fn level_and_children(nodes: &mut [Node], level_start: usize) -> (&mut [Node], &[Node]) {
+    let (above, children) = nodes.split_at_mut(level_start * 2);
+    (&mut above[level_start..], children)
+}
+
+/// The node for the `i`-th node of a level, given the level below it.
+///
+/// `leaves_len` is only used to check the result in debug builds.
+fn parent_node(children: &[Node], i: usize, leaves_len: usize) -> Node {
+    let left = children.get(i * 2).copied().and_then(Node::into_expanded);
+    let right = children
+        .get(i * 2 + 1)
+        .copied()
+        .and_then(Node::into_expanded);
+
+    merge_children(left, right, leaves_len)
+}
+
+/// The node enclosing `left` and `right`, where `None` is a child past the end of the tree.
+///
+/// `leaves_len` is only used to check the result in debug builds.
+#[allow(clippy::cast_possible_truncation)]
+fn merge_children(left: Option<Expanded>, right: Option<Expanded>, leaves_len: usize) -> Node {
+    let parent_node = match (left, right) {
+        (Some(Expanded::Aabb(left)), Some(Expanded::Aabb(right))) => {
+            let aabb = left.merge(right);
+            Node::aabb(aabb)
+        }
+        (Some(Expanded::Aabb(left)), Some(Expanded::Leaf(right))) => {
+            let aabb = left.enclose(right.point);
+            Node::aabb(aabb)
+        }
+        (Some(Expanded::Aabb(left)), ..) => {
+            // todo: try to restructure to eliminate this branch
+            Node::aabb(left)
+        }
+        (Some(Expanded::Leaf(left)), Some(Expanded::Leaf(right))) // valid, valid
+            if left.is_valid() && right.is_valid() =>
+        {
+            // after a refit two leaves can share a point
+            let aabb = Aabb::enclosing_aabb([left.point, right.point]);
+            Node::aabb(aabb)
+        }
+        (Some(Expanded::Leaf(left)), _) // valid, invalid
+            if left.is_valid() =>
+        {
+            Node::from(left)
+        }
+        (left, right) => {
+            #[cfg(debug_assertions)]
+            {
+                if let Some(left) = left {
+                    let Expanded::Leaf(left) = left else { unreachable!() };
+                    debug_assert!(left.is_invalid(), "expected invalid left leaf, got {left:?}");
+                }
+
+                if let Some(right) = right {
+                    let Expanded::Leaf(right) = right else { unreachable!() };
+                    debug_assert!(right.is_invalid(), "expected invalid right leaf, got {right:?}");
+                }
+
+            }
+            Node::from(LeafPtr::INVALID)
+        },
+    };
+
+    #[cfg(debug_assertions)]
+    {
+        if let Some(Expanded::Leaf(leaf)) = parent_node.into_expanded() {
+            debug_assert_lt!(
+                    leaf.ptr,
+                    leaves_len as u32,
+                    "leaf.ptr {} is out of bounds for leaves.len() of {}, left leaf: {left:?}, right leaf: {right:?}",
+                    leaf.ptr,
+                    leaves_len
+            );
+        }
+    }
+
+    #[cfg(not(debug_assertions))]
+    let _ = leaves_len;
+
+    parent_node
+}
+
 impl<T, A: Allocator> Bvh<Vec<T, A>, A> {
     pub fn elements(&self) -> &[T] {
         &self.data
@@ -268,11 +462,130 @@ impl<L, A: Allocator> Bvh<L, A> {
     #[allow(clippy::missing_panics_doc)]
     pub unsafe fn get_node(&self, idx: u32) -> Node {
         debug_assert_lt!(u64::from(idx), u64::try_from(self.nodes.len()).unwrap());
+
+        #[cfg(feature = "stats")]
+        self.nodes_visited.fetch_add(1, Ordering::Relaxed);
+
         let ptr = self.nodes.get_unchecked(idx as usize);
         ptr.get()
     }
+
+    /// The range of elements in `data` that belongs to the leaf at `ptr`.
+    fn leaf_range(&self, ptr: u32) -> Range<u32> {
+        let start = self.leaves[ptr as usize].element_index;
+        let end = self.leaves[ptr as usize + 1].element_index;
+        start..end
+    }
+
+    /// The number of leaves, not counting the sentinel at the end of `leaves`.
+    #[allow(clippy::cast_possible_truncation)]
+    const fn leaf_count(&self) -> u32 {
+        self.leaves.len().saturating_sub(1) as u32
+    }
+
+    /// The index of the first leaf node; inner nodes are all below it.
+    #[allow(clippy::cast_possible_truncation)]
+    fn leaves_next_pow2(&self) -> u32 {
+        self.nodes.len() as u32 - self.leaf_count()
+    }
+
+    /// The point of the leaf at `ptr`.
+    fn leaf_point(&self, ptr: u32) -> glam::I16Vec2 {
+        // read directly so iterating leaves does not count towards `nodes_visited`
+        let node = self.nodes[(self.leaves_next_pow2() + ptr) as usize].get();
+        let leaf = node.leaf_element_indices();
+        debug_assert!(leaf.is_some(), "expected leaf at {ptr}, got {node:?}");
+        unsafe { leaf.unwrap_unchecked() }.point
+    }
+
+    /// The left child of the inner node at `idx`, for walking the tree with [`Self::get_node`].
+    ///
+    /// This is [`child_left`] for the implicit layout and works for every layout.
+    #[must_use]
+    #[inline]
+    pub fn left_child(&self, idx: u32) -> u32 {
+        match &self.layout {
+            Layout::Implicit => child_left(idx),
+            Layout::Explicit(children) => children[idx as usize][0],
+        }
+    }
+
+    /// The right child of the inner node at `idx`, see [`Self::left_child`].
+    #[must_use]
+    #[inline]
+    pub fn right_child(&self, idx: u32) -> u32 {
+        match &self.layout {
+            Layout::Implicit => child_right(idx),
+            Layout::Explicit(children) => children[idx as usize][1],
+        }
+    }
+
+    /// Whether the children of `i` are `2i` and `2i + 1`, which [`Self::refit`] and the fast
+    /// path of [`Bvh::rebuild`] rely on.
+    #[must_use]
+    pub const fn is_implicit(&self) -> bool {
+        matches!(self.layout, Layout::Implicit)
+    }
+
+    /// The leaves below the node at `idx`, which are always contiguous.
+    fn subtree_leaves(&self, idx: u32) -> Range<u32> {
+        let len = self.leaf_count();
+        let leaves_next_pow2 = self.leaves_next_pow2();
+
+        if !self.is_implicit() {
+            // leaves come after all inner nodes, follow the outermost paths down to them
+            let mut first = idx;
+            let mut last = idx;
+
+            while first < leaves_next_pow2 {
+                first = self.left_child(first);
+            }
+
+            while last < leaves_next_pow2 {
+                last = self.right_child(last);
+            }
+
+            return first - leaves_next_pow2..last - leaves_next_pow2 + 1;
+        }
+
+        let shift = leaves_next_pow2.ilog2() - idx.ilog2();
+
+        let start = (idx << shift) - leaves_next_pow2;
+        let end = ((idx + 1) << shift) - leaves_next_pow2;
+
+        start..end.min(len)
+    }
+
+    /// The range of elements in `data` that belongs to all leaves below the node at `idx`.
+    fn subtree_range(&self, idx: u32) -> Range<u32> {
+        let leaves = self.subtree_leaves(idx);
+        let start = self.leaves[leaves.start as usize].element_index;
+        let end = self.leaves[leaves.end as usize].element_index;
+        start..end
+    }
 }
 
+#[cfg(feature = "stats")]
+impl<L, A: Allocator> Bvh<L, A> {
+    /// The number of node reads through [`Self::get_node`] since the tree was built or the
+    /// counter was last reset, for comparing layouts on a workload.
+    ///
+    /// This counts reads, not distinct nodes: a query that reads a node twice, such as the root
+    /// before its traversal starts, counts it twice. Iterating leaves is not counted.
+    pub fn nodes_visited(&self) -> u64 {
+        self.nodes_visited.load(Ordering::Relaxed)
+    }
+
+    /// Sets [`Self::nodes_visited`] back to zero, e.g. before running the workload to measure.
+    pub fn reset_nodes_visited(&self) {
+        self.nodes_visited.store(0, Ordering::Relaxed);
+    }
+}
+
+/// The left child of `idx` in the implicit layout.
+///
+/// Trees built with [`Bvh::build_sah_in`] store their children explicitly, so use
+/// [`Bvh::left_child`] unless [`Bvh::is_implicit`].
 #[must_use]
 pub const fn child_left(idx: u32) -> u32 {
     idx * 2
@@ -283,6 +596,7 @@ pub const fn parent(idx: u32) -> Option<NonZeroU32> {
     NonZeroU32::new(idx / 2)
 }
 
+/// The right child of `idx` in the implicit layout, see [`child_left`].
 #[must_use]
 pub const fn child_right(idx: u32) -> u32 {
     idx * 2 + 1
@@ -338,9 +652,76 @@ where
     (result_data, indices, points)
 }
 
+/// [`process_input`] where the lengths are computed and the data is copied on the rayon thread
+/// pool.
+#[cfg(feature = "parallel")]
+#[allow(clippy::cast_possible_truncation)]
+fn par_process_input<'c, I, T, A>(
+    input: &[I],
+    alloc: A,
+    context: I::Context<'c>,
+) -> (Vec<T, A>, Vec<Leaf, A>, Vec<glam::I16Vec2, A>)
+where
+    I: PointWithData<Unit = T> + Sync,
+    I::Context<'c>: Sync,
+    T: Copy + Send + Sync + 'static,
+    A: Allocator + Clone,
+{
+    use rayon::prelude::*;
+
+    // `data` is called once per element, so the copied slices match the lengths counted here
+    let data: Vec<&[T]> = input.par_iter().map(|elem| elem.data(context)).collect();
+
+    let mut indices = Vec::new_in(alloc.clone());
+    let mut points = Vec::new_in(alloc.clone());
+    let mut current_point = None;
+    let mut total = 0;
+
+    for (elem, data) in input.iter().zip(&data) {
+        let point = elem.point();
+
+        if Some(point) != current_point {
+            indices.push(Leaf::new(total as u32));
+            points.push(point);
+        }
+
+        total += data.len();
+        current_point = Some(point);
+    }
+
+    let mut result_data = Vec::with_capacity_in(total, alloc);
+
+    // one disjoint chunk of the output per element
+    let mut chunks = Vec::with_capacity(input.len());
+    let mut rest = &mut result_data.spare_capacity_mut()[..total];
+
+    for slice in &data {
+        let (chunk, tail) = rest.split_at_mut(slice.len());
+        chunks.push(chunk);
+        rest = tail;
+    }
+
+    chunks
+        .into_par_iter()
+        .zip(data.into_par_iter())
+        .for_each(|(chunk, slice)| {
+            for (out, &data) in chunk.iter_mut().zip(slice) {
+                out.write(data);
+            }
+        });
+
+    // every chunk was written in full, and together they cover `..total`
+    unsafe { result_data.set_len(total) };
+
+    (result_data, indices, points)
+}
+
 #[cfg(test)]
 mod tests {
+    #[cfg(feature = "parallel")]
+    use crate::node::Node;
     use crate::process_input;
+    use crate::Bvh;
     use crate::Data;
     use crate::Leaf;
     use crate::Point;
@@ -441,6 +822,31 @@ mod tests {
         assert_eq!(points, vec![I16Vec2::new(5, 5)]);
     }
 
+    #[test]
+    fn test_subtree_leaves() {
+        let mut input: Vec<_> = (0..5)
+            .map(|i| TestPoint {
+                point: I16Vec2::new(i, i),
+                data: vec![u8::try_from(i).unwrap(); 2],
+            })
+            .collect();
+
+        // 01
+        // 02          03
+        // 04    05    06 -> leaf 4
+        // 08 09 10 11 12
+        let bvh = Bvh::build(&mut input, ());
+
+        assert_eq!(bvh.subtree_leaves(1), 0..5);
+        assert_eq!(bvh.subtree_leaves(2), 0..4);
+        assert_eq!(bvh.subtree_leaves(3), 4..5);
+        assert_eq!(bvh.subtree_leaves(5), 2..4);
+        assert_eq!(bvh.subtree_leaves(9), 1..2);
+
+        assert_eq!(bvh.subtree_range(5), 4..8);
+        assert_eq!(bvh.subtree_range(3), 8..10);
+    }
+
     #[test]
     fn test_process_input_all_unique_points() {
         let input = vec![
@@ -491,4 +897,34 @@ mod tests {
         assert_eq!(indices, vec![Leaf::new(0), Leaf::new(2)]);
         assert_eq!(points, vec![I16Vec2::new(0, 0), I16Vec2::new(1, 1)]);
     }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_par_build_is_identical() {
+        let mut rng = fastrand::Rng::with_seed(42);
+
+        for len in [0, 1, 2, 3, 7, 64, 1000, 10_000] {
+            let input: Vec<_> = (0..len)
+                .map(|_| TestPoint {
+                    point: I16Vec2::new(rng.i16(-50..50), rng.i16(-50..50)),
+                    data: (0..rng.u8(0..4)).map(|_| rng.u8(..)).collect(),
+                })
+                .collect();
+
+            let serial = Bvh::build(&mut input.clone(), ());
+            let parallel = Bvh::par_build(&mut input.clone(), ());
+
+            // `Node` is a union, so compare the raw bits
+            let bits = |bvh: &Bvh<Vec<u8>>| -> Vec<u64> {
+                bvh.nodes
+                    .iter()
+                    .map(|node| unsafe { std::mem::transmute::<Node, u64>(node.get()) })
+                    .collect()
+            };
+
+            assert_eq!(bits(&serial), bits(&parallel), "len {len}");
+            assert_eq!(serial.leaves, parallel.leaves, "len {len}");
+            assert_eq!(serial.data, parallel.data, "len {len}");
+        }
+    }
 }
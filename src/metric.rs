@@ -0,0 +1,104 @@
+//! Distance metrics used by nearest-neighbour and radius queries.
+//!
+//! Distances are only ever compared with each other, so a metric is free to return any value that
+//! is monotonic in the real distance (e.g. [`Euclidean`] returns the squared distance).
+use glam::{I16Vec2, UVec2};
+
+use crate::aabb::Aabb;
+
+pub trait Metric: Copy {
+    /// The distance of a vector given its per-axis absolute lengths.
+    ///
+    /// Has to be monotonic in both axes for node pruning to be correct.
+    fn norm(self, lens: UVec2) -> u32;
+
+    /// Converts a radius into the units returned by [`Metric::norm`].
+    fn radius(self, radius: u16) -> u32 {
+        u32::from(radius)
+    }
+
+    /// The factor by which a distance returned by [`Metric::norm`] grows when the real distance
+    /// grows by `1 + epsilon`.
+    fn tolerance(self, epsilon: f32) -> f64 {
+        1.0 + f64::from(epsilon)
+    }
+
+    fn distance(self, a: I16Vec2, b: I16Vec2) -> u32 {
+        #[allow(clippy::cast_sign_loss)]
+        let lens = (a.as_ivec2() - b.as_ivec2()).abs().as_uvec2();
+        self.norm(lens)
+    }
+
+    /// The minimum and maximum distance from `point` to anything inside `aabb`.
+    fn min_max_distance(self, aabb: Aabb, point: I16Vec2) -> (u32, u32) {
+        let (min_lens, max_lens) = aabb.min_max_lens(point);
+        (self.norm(min_lens), self.norm(max_lens))
+    }
+}
+
+/// Squared Euclidean distance.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Euclidean;
+
+impl Metric for Euclidean {
+    fn norm(self, lens: UVec2) -> u32 {
+        lens.length_squared()
+    }
+
+    fn radius(self, radius: u16) -> u32 {
+        u32::from(radius).pow(2)
+    }
+
+    fn tolerance(self, epsilon: f32) -> f64 {
+        (1.0 + f64::from(epsilon)).powi(2)
+    }
+}
+
+/// Chebyshev (chessboard) distance, i.e. a square radius.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Chebyshev;
+
+impl Metric for Chebyshev {
+    fn norm(self, lens: UVec2) -> u32 {
+        lens.max_element()
+    }
+}
+
+/// Manhattan (taxicab) distance.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct Manhattan;
+
+impl Metric for Manhattan {
+    fn norm(self, lens: UVec2) -> u32 {
+        lens.element_sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_distances() {
+        let a = I16Vec2::new(-1, 2);
+        let b = I16Vec2::new(2, -2);
+
+        assert_eq!(Euclidean.distance(a, b), 25);
+        assert_eq!(Chebyshev.distance(a, b), 4);
+        assert_eq!(Manhattan.distance(a, b), 7);
+    }
+
+    #[test]
+    fn test_min_max_distance() {
+        let aabb = Aabb::new(I16Vec2::new(0, 0), I16Vec2::new(2, 4));
+        let point = I16Vec2::new(-3, 1);
+
+        assert_eq!(Euclidean.min_max_distance(aabb, point), (9, 34));
+        assert_eq!(Chebyshev.min_max_distance(aabb, point), (3, 5));
+        assert_eq!(Manhattan.min_max_distance(aabb, point), (3, 8));
+
+        // inside
+        let point = I16Vec2::new(1, 1);
+        assert_eq!(Manhattan.min_max_distance(aabb, point), (0, 4));
+    }
+}
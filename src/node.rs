@@ -81,7 +81,7 @@ impl Leaf {
 
 const MSB_1_MASK: u32 = 0x8000_0000;
 
-#[derive(Debug, Copy, Clone)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Expanded {
     Aabb(Aabb),
     Leaf(LeafPtr),
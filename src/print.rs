@@ -1,6 +1,6 @@
 use crate::node::Expanded;
 use crate::Aabb;
-use crate::{child_left, child_right, Bvh, ROOT_IDX};
+use crate::{Bvh, ROOT_IDX};
 use std::alloc::Allocator;
 use std::collections::VecDeque;
 use std::fmt::Debug;
@@ -44,8 +44,8 @@ impl<T: Debug, A: Allocator> Bvh<Vec<T, A>, A> {
 
                     output.push_str(&format!("{idx:02}\t{indent}Internal({aabb:?})\n"));
 
-                    let left = child_left(idx);
-                    let right = child_right(idx);
+                    let left = self.left_child(idx);
+                    let right = self.right_child(idx);
 
                     queue.push_back(Element {
                         idx: left,
@@ -1,4 +1,6 @@
 use std::alloc::Allocator;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
 use std::ops::Range;
 
 use arrayvec::ArrayVec;
@@ -6,14 +8,156 @@ use bytes::Bytes;
 use glam::I16Vec2;
 use heapless::binary_heap::Min;
 
-use crate::aabb::Aabb;
 use crate::node::Expanded;
-use crate::{child_left, child_right, Bvh, ROOT_IDX};
+use crate::{Bvh, Difference, Euclidean, Metric, QueryShape, ROOT_IDX};
+
+mod batch;
+mod buckets;
+mod budget;
+mod closest_where;
+mod count;
+mod diff;
+mod gather;
+mod iter;
+mod join;
+mod leaves;
+mod radius;
+mod ray;
+mod vectored;
+
+pub use budget::Budgeted;
+pub use diff::InDiff;
+pub use gather::Framing;
+pub use ray::RayHit;
+pub use vectored::write_all_vectored;
+
+use iter::InIter;
 
 const MAX_SIZE: usize = 32;
 const DFS_STACK_SIZE: usize = 32;
 const HEAP_SIZE: usize = 32;
 
+#[derive(Debug, Copy, Clone)]
+struct MinNode {
+    dist: u32,
+    expanded: Expanded,
+    idx: u32,
+}
+
+impl PartialEq for MinNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.dist == other.dist
+    }
+}
+
+impl Eq for MinNode {}
+
+impl Ord for MinNode {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.dist.cmp(&other.dist)
+    }
+}
+
+impl PartialOrd for MinNode {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+/// The best-first search shared by [`Bvh::get_closest_with`] and
+/// [`Bvh::get_closest_approx_with`].
+struct ClosestSearch<M> {
+    input: I16Vec2,
+    metric: M,
+    /// No leaf further than this can be the closest one.
+    max_distance_to_closest: u32,
+    heap: heapless::BinaryHeap<MinNode, Min, HEAP_SIZE>,
+}
+
+impl<M: Metric> ClosestSearch<M> {
+    /// Starts at the root of `bvh`, `None` if there is nothing to find.
+    fn new<L: Len, A: Allocator>(bvh: &Bvh<L, A>, input: I16Vec2, metric: M) -> Option<Self> {
+        if bvh.data.is_empty() {
+            return None;
+        }
+
+        let node = unsafe { bvh.get_node(ROOT_IDX) };
+
+        let mut heap = heapless::BinaryHeap::new();
+
+        // a root leaf is popped right away
+        heap.push(MinNode {
+            dist: u32::MAX,
+            expanded: node.into_expanded().expect("root node is always valid"),
+            idx: ROOT_IDX,
+        })
+        .unwrap();
+
+        Some(Self {
+            input,
+            metric,
+            max_distance_to_closest: u32::MAX,
+            heap,
+        })
+    }
+
+    /// Pushes the node at `idx` unless it is invalid or can not hold the closest leaf, and
+    /// returns it if it was pushed.
+    fn push<L, A: Allocator>(&mut self, bvh: &Bvh<L, A>, idx: u32) -> Option<MinNode> {
+        let node = unsafe { bvh.get_node(idx) };
+        let expanded = node.into_expanded()?;
+
+        let (dist_min, dist_max) = match expanded {
+            Expanded::Aabb(aabb) => self.metric.min_max_distance(aabb, self.input),
+            Expanded::Leaf(leaf) => {
+                if leaf.is_invalid() {
+                    return None;
+                }
+
+                let dist = self.metric.distance(leaf.point, self.input);
+                (dist, dist)
+            }
+        };
+
+        if self.max_distance_to_closest < dist_min {
+            return None;
+        }
+
+        self.max_distance_to_closest = self.max_distance_to_closest.min(dist_max);
+
+        let node = MinNode {
+            dist: dist_min,
+            expanded,
+            idx,
+        };
+
+        self.heap.push(node).unwrap();
+
+        Some(node)
+    }
+}
+
+/// The result of [`Bvh::get_closest_approx`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClosestApprox {
+    /// The range of the closest leaf that was found.
+    pub range: Range<u32>,
+    /// Whether `range` is proven to be the closest leaf.
+    pub exact: bool,
+}
+
+/// Appends `range` to `ranges`, extending the last range instead if they are adjacent.
+fn push_merged(ranges: &mut Vec<Range<u32>>, range: Range<u32>) {
+    if range.is_empty() {
+        return;
+    }
+
+    match ranges.last_mut() {
+        Some(last) if last.end == range.start => last.end = range.end,
+        _ => ranges.push(range),
+    }
+}
+
 impl<A: Allocator> Bvh<Bytes, A> {
     pub fn get_closest_slice_bytes(&self, input: I16Vec2) -> Option<Bytes> {
         let idx = self.get_closest(input)?;
@@ -21,12 +165,27 @@ impl<A: Allocator> Bvh<Bytes, A> {
         Some(self.data.slice(idx))
     }
 
-    pub fn get_in_slices_bytes(&self, query: Aabb) -> ArrayVec<Bytes, DFS_STACK_SIZE> {
+    pub fn get_k_closest_slices_bytes(&self, input: I16Vec2, k: usize) -> Vec<Bytes> {
+        self.get_k_closest(input, k)
+            .into_iter()
+            .map(|range| self.data.slice(range.start as usize..range.end as usize))
+            .collect()
+    }
+
+    pub fn get_in_slices_bytes(&self, query: impl QueryShape) -> ArrayVec<Bytes, DFS_STACK_SIZE> {
         self.get_in(query)
             .into_iter()
             .map(|range| self.data.slice(range.start as usize..range.end as usize))
             .collect()
     }
+
+    pub fn get_in_slices_bytes_iter<'a>(
+        &'a self,
+        query: impl QueryShape + 'a,
+    ) -> impl Iterator<Item = Bytes> + 'a {
+        self.get_in_iter(query)
+            .map(|range| self.data.slice(range.start as usize..range.end as usize))
+    }
 }
 
 impl<T, A: Allocator> Bvh<Vec<T>, A> {
@@ -36,12 +195,27 @@ impl<T, A: Allocator> Bvh<Vec<T>, A> {
         Some(&self.data[idx])
     }
 
-    pub fn get_in_slices(&self, query: Aabb) -> ArrayVec<&[T], DFS_STACK_SIZE> {
+    pub fn get_k_closest_slices(&self, input: I16Vec2, k: usize) -> Vec<&[T]> {
+        self.get_k_closest(input, k)
+            .into_iter()
+            .map(|range| &self.data[range.start as usize..range.end as usize])
+            .collect()
+    }
+
+    pub fn get_in_slices(&self, query: impl QueryShape) -> ArrayVec<&[T], DFS_STACK_SIZE> {
         self.get_in(query)
             .into_iter()
             .map(|range| &self.data[range.start as usize..range.end as usize])
             .collect()
     }
+
+    pub fn get_in_slices_iter<'a>(
+        &'a self,
+        query: impl QueryShape + 'a,
+    ) -> impl Iterator<Item = &'a [T]> + 'a {
+        self.get_in_iter(query)
+            .map(|range| &self.data[range.start as usize..range.end as usize])
+    }
 }
 
 pub trait Len {
@@ -70,6 +244,11 @@ impl Bvh<Vec<u8>> {
             nodes: self.nodes,
             data: self.data.into(),
             leaves: self.leaves,
+            cost: self.cost,
+            input_len: self.input_len,
+            layout: self.layout,
+            #[cfg(feature = "stats")]
+            nodes_visited: self.nodes_visited,
         }
     }
 }
@@ -77,146 +256,265 @@ impl Bvh<Vec<u8>> {
 impl<L: Len, A: Allocator> Bvh<L, A> {
     /// # Panics
     /// If there are too many elements that overflow `HEAP_SIZE`
-    #[allow(clippy::too_many_lines)]
     pub fn get_closest(&self, input: I16Vec2) -> Option<Range<u32>> {
-        #[derive(Debug, Copy, Clone)]
-        struct MinNode {
-            dist2: u32,
-            expanded: Expanded,
-            idx: u32,
-        }
-
-        impl PartialEq for MinNode {
-            fn eq(&self, other: &Self) -> bool {
-                self.dist2 == other.dist2
-            }
-        }
-
-        impl Eq for MinNode {}
+        self.get_closest_with(input, Euclidean)
+    }
 
-        impl Ord for MinNode {
-            fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-                self.dist2.cmp(&other.dist2)
-            }
-        }
+    /// [`Self::get_closest`] using `metric` instead of the Euclidean distance.
+    ///
+    /// # Panics
+    /// If there are too many elements that overflow `HEAP_SIZE`
+    pub fn get_closest_with<M: Metric>(&self, input: I16Vec2, metric: M) -> Option<Range<u32>> {
+        let mut search = ClosestSearch::new(self, input, metric)?;
 
-        impl PartialOrd for MinNode {
-            fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-                Some(self.cmp(other))
+        while let Some(context) = search.heap.pop() {
+            match context.expanded {
+                Expanded::Leaf(leaf) => return Some(self.leaf_range(leaf.ptr)),
+                Expanded::Aabb(..) => {
+                    search.push(self, self.left_child(context.idx));
+                    search.push(self, self.right_child(context.idx));
+                }
             }
         }
 
-        let mut max_distance_to_closest = u32::MAX;
+        None
+    }
 
-        let mut new_node = |idx: u32, expanded: Expanded| match expanded {
-            Expanded::Aabb(aabb) => {
-                let (dist2_min, dist2_max) = aabb.min_max_distance2(input);
+    /// Approximate [`Self::get_closest`] with bounded work.
+    ///
+    /// The search stops after expanding `max_visits` nodes, or as soon as the closest leaf found
+    /// so far is within `1 + epsilon` times the distance of the closest leaf that could still be
+    /// found. If no leaf has been found when the budget runs out, the closest child is followed
+    /// down to a leaf.
+    ///
+    /// # Panics
+    /// If there are too many elements that overflow `HEAP_SIZE`
+    pub fn get_closest_approx(
+        &self,
+        input: I16Vec2,
+        max_visits: usize,
+        epsilon: f32,
+    ) -> Option<ClosestApprox> {
+        self.get_closest_approx_with(input, max_visits, epsilon, Euclidean)
+    }
 
-                if max_distance_to_closest < dist2_min {
-                    return None;
+    /// [`Self::get_closest_approx`] using `metric` instead of the Euclidean distance.
+    ///
+    /// # Panics
+    /// If there are too many elements that overflow `HEAP_SIZE`
+    pub fn get_closest_approx_with<M: Metric>(
+        &self,
+        input: I16Vec2,
+        max_visits: usize,
+        epsilon: f32,
+        metric: M,
+    ) -> Option<ClosestApprox> {
+        let tolerance = metric.tolerance(epsilon);
+
+        let mut search = ClosestSearch::new(self, input, metric)?;
+
+        // (distance, ptr) of the closest leaf pushed so far
+        let mut closest_leaf: Option<(u32, u32)> = None;
+        let mut visits = 0;
+
+        while let Some(context) = search.heap.pop() {
+            match context.expanded {
+                Expanded::Leaf(leaf) => {
+                    return Some(ClosestApprox {
+                        range: self.leaf_range(leaf.ptr),
+                        exact: true,
+                    });
                 }
+                Expanded::Aabb(..) => {
+                    // `context.dist` is a lower bound for every leaf that is still in the heap
+                    if let Some((dist, ptr)) = closest_leaf {
+                        if f64::from(dist) <= f64::from(context.dist) * tolerance {
+                            return Some(ClosestApprox {
+                                range: self.leaf_range(ptr),
+                                exact: dist <= context.dist,
+                            });
+                        }
+                    }
 
-                if dist2_max < max_distance_to_closest {
-                    max_distance_to_closest = dist2_max;
-                }
+                    if visits == max_visits {
+                        let (ptr, exact) = match closest_leaf {
+                            Some((dist, ptr)) => (ptr, dist <= context.dist),
+                            None => (self.descend_closest(context.idx, input, metric), false),
+                        };
 
-                Some(MinNode {
-                    dist2: dist2_min,
-                    expanded,
-                    idx,
-                })
-            }
-            Expanded::Leaf(leaf) => {
-                if leaf.is_invalid() {
-                    return None;
-                }
+                        return Some(ClosestApprox {
+                            range: self.leaf_range(ptr),
+                            exact,
+                        });
+                    }
 
-                #[allow(clippy::cast_sign_loss)]
-                let difference = (leaf.point.as_ivec2() - input.as_ivec2()).abs().as_uvec2();
-                let dist2 = difference.length_squared();
+                    visits += 1;
 
-                if max_distance_to_closest < dist2 {
-                    return None;
-                }
+                    for idx in [self.left_child(context.idx), self.right_child(context.idx)] {
+                        let Some(node) = search.push(self, idx) else {
+                            continue;
+                        };
 
-                if dist2 < max_distance_to_closest {
-                    max_distance_to_closest = dist2;
+                        if let Expanded::Leaf(leaf) = node.expanded {
+                            if closest_leaf.is_none_or(|(dist, _)| node.dist < dist) {
+                                closest_leaf = Some((node.dist, leaf.ptr));
+                            }
+                        }
+                    }
                 }
-
-                Some(MinNode {
-                    dist2,
-                    expanded,
-                    idx,
-                })
             }
-        };
-
-        if self.data.is_empty() {
-            return None;
         }
 
-        let mut heap: heapless::BinaryHeap<MinNode, Min, HEAP_SIZE> = heapless::BinaryHeap::new();
+        None
+    }
 
-        // root idx
-        let node = unsafe { self.get_node(ROOT_IDX) };
-        let expanded_node = node.into_expanded().expect("root node is always valid");
+    /// Greedily follows the child closest to `input` from `idx` down to a leaf.
+    fn descend_closest<M: Metric>(&self, mut idx: u32, input: I16Vec2, metric: M) -> u32 {
+        loop {
+            let node = unsafe { self.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => return leaf.ptr,
+                Some(Expanded::Aabb(..)) => {
+                    let dist = |idx: u32| {
+                        let node = unsafe { self.get_node(idx) };
+
+                        match node.into_expanded() {
+                            Some(Expanded::Aabb(aabb)) => metric.min_max_distance(aabb, input).0,
+                            Some(Expanded::Leaf(leaf)) => metric.distance(leaf.point, input),
+                            None => u32::MAX,
+                        }
+                    };
 
-        if let Expanded::Leaf(leaf) = expanded_node {
-            // if root node is a leaf, return the leaf
+                    let left = self.left_child(idx);
+                    let right = self.right_child(idx);
 
-            let ptr = leaf.ptr;
-            let start = self.leaves[ptr as usize].element_index;
-            let end = self.leaves[ptr as usize + 1].element_index;
+                    // the left child of an inner node is always valid
+                    idx = if dist(right) < dist(left) {
+                        right
+                    } else {
+                        left
+                    };
+                }
+                None => unreachable!("descended into an invalid node"),
+            }
+        }
+    }
 
-            println!("returning leaf {start}..{end}");
+    /// Returns the ranges of up to `k` leaves, closest first.
+    ///
+    /// Like [`Self::get_closest`] this is a best-first search over `min_max_distance2`. Nodes are
+    /// pruned once their minimum distance is larger than the `k`-th closest leaf seen so far.
+    ///
+    /// `k` may be larger than the number of leaves, e.g. `usize::MAX` to sort all of them.
+    pub fn get_k_closest(&self, input: I16Vec2, k: usize) -> Vec<Range<u32>> {
+        self.get_k_closest_with(input, k, Euclidean)
+    }
 
-            return Some(start..end);
+    /// [`Self::get_k_closest`] using `metric` instead of the Euclidean distance.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get_k_closest_with<M: Metric>(
+        &self,
+        input: I16Vec2,
+        k: usize,
+        metric: M,
+    ) -> Vec<Range<u32>> {
+        // there are never more than `leaf_count` results, so `usize::MAX` means "all"
+        let k = k.min(self.leaf_count() as usize);
+        let mut result = Vec::with_capacity(k);
+
+        if self.data.is_empty() || k == 0 {
+            return result;
         }
 
-        let dist2 = u32::MAX;
+        // distances of the `k` closest leaves pushed so far (max-heap)
+        let mut closest_k: BinaryHeap<u32> = BinaryHeap::with_capacity(k);
+        let mut heap: BinaryHeap<Reverse<MinNode>> = BinaryHeap::new();
 
-        heap.push(MinNode {
-            dist2,
+        let node = unsafe { self.get_node(ROOT_IDX) };
+
+        heap.push(Reverse(MinNode {
+            dist: 0,
             expanded: node.into_expanded().expect("root node is always valid"),
             idx: ROOT_IDX,
-        })
-        .unwrap();
+        }));
 
-        while let Some(context) = heap.pop() {
+        while let Some(Reverse(context)) = heap.pop() {
             match context.expanded {
                 Expanded::Leaf(leaf) => {
-                    let ptr = leaf.ptr;
-                    let start = self.leaves[ptr as usize].element_index;
-                    let end = self.leaves[ptr as usize + 1].element_index;
+                    result.push(self.leaf_range(leaf.ptr));
 
-                    return Some(start..end);
+                    if result.len() == k {
+                        break;
+                    }
                 }
                 Expanded::Aabb(..) => {
-                    let left = child_left(context.idx);
+                    for idx in [self.left_child(context.idx), self.right_child(context.idx)] {
+                        let node = unsafe { self.get_node(idx) };
+
+                        let Some(expanded) = node.into_expanded() else {
+                            continue;
+                        };
 
-                    let node = unsafe { self.get_node(left) };
+                        let dist = match expanded {
+                            Expanded::Aabb(aabb) => metric.min_max_distance(aabb, input).0,
+                            Expanded::Leaf(leaf) => metric.distance(leaf.point, input),
+                        };
 
-                    if let Some(node) = node.into_expanded() {
-                        if let Some(node) = new_node(left, node) {
-                            heap.push(node).unwrap();
+                        let full = closest_k.len() == k;
+
+                        if full && closest_k.peek().is_some_and(|&kth| kth < dist) {
+                            continue;
                         }
-                    }
 
-                    let right = child_right(context.idx);
-                    let node = unsafe { self.get_node(right) };
-                    if let Some(node) = node.into_expanded() {
-                        if let Some(node) = new_node(right, node) {
-                            heap.push(node).unwrap();
+                        if let Expanded::Leaf(..) = expanded {
+                            if full {
+                                closest_k.pop();
+                            }
+                            closest_k.push(dist);
                         }
+
+                        heap.push(Reverse(MinNode {
+                            dist,
+                            expanded,
+                            idx,
+                        }));
                     }
                 }
             }
         }
 
-        None
+        result
+    }
+
+    /// Lazily yields the merged ranges of all leaves inside `query`.
+    ///
+    /// Unlike [`Self::get_in`] there is no limit on the number of disjoint ranges.
+    /// Leaves without any data are skipped.
+    pub fn get_in_iter<'a>(
+        &'a self,
+        query: impl QueryShape + 'a,
+    ) -> impl Iterator<Item = Range<u32>> + 'a {
+        InIter::new(self, query)
+    }
+
+    /// Appends the merged ranges of all leaves inside `query` to `out`.
+    pub fn get_in_into(&self, query: impl QueryShape, out: &mut Vec<Range<u32>>) {
+        out.extend(self.get_in_iter(query));
+    }
+
+    /// Returns the merged ranges of all leaves inside `outer` but not inside `inner`.
+    ///
+    /// Same as querying [`Difference`], which can also be combined with any other shape.
+    pub fn get_in_difference(
+        &self,
+        outer: impl QueryShape,
+        inner: impl QueryShape,
+    ) -> Vec<Range<u32>> {
+        self.get_in_iter(Difference::new(outer, inner)).collect()
     }
 
-    pub fn get_in(&self, query: Aabb) -> ArrayVec<Range<u32>, DFS_STACK_SIZE> {
+    pub fn get_in<Q: QueryShape>(&self, query: Q) -> ArrayVec<Range<u32>, DFS_STACK_SIZE> {
         let mut to_send_indices: ArrayVec<Range<u32>, MAX_SIZE> = ArrayVec::new();
 
         if self.data.is_empty() {
@@ -254,12 +552,12 @@ impl<L: Len, A: Allocator> Bvh<L, A> {
                     }
                 }
                 Some(Expanded::Aabb(aabb)) => {
-                    if !aabb.intersects(query) {
+                    if !query.intersects_aabb(aabb) {
                         continue;
                     }
 
-                    let left = child_left(idx);
-                    let right = child_right(idx);
+                    let left = self.left_child(idx);
+                    let right = self.right_child(idx);
 
                     dfs_stack.push(right);
 
@@ -0,0 +1,99 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use crate::node::Expanded;
+use crate::query::{push_merged, Len};
+use crate::{Bvh, QueryShape, ROOT_IDX};
+
+#[derive(Debug, Clone)]
+struct Frame {
+    idx: u32,
+    /// the queries that intersect the parent of `idx` (a range into the active query stack)
+    queries: Range<u32>,
+}
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Runs [`Self::get_in`] for every query in `queries` with a single traversal of the tree.
+    ///
+    /// `result[i]` holds the merged ranges for `queries[i]`.
+    pub fn get_in_batch<Q: QueryShape>(&self, queries: &[Q]) -> Vec<Vec<Range<u32>>> {
+        let mut result = Vec::new();
+        self.get_in_batch_into(queries, &mut result);
+        result
+    }
+
+    /// Like [`Self::get_in_batch`] but reuses the allocations in `out`.
+    ///
+    /// `out` is resized to `queries.len()` and every inner `Vec` is cleared first.
+    ///
+    /// Every node is tested once against all queries that intersect its parent, so queries that
+    /// overlap share the work of descending through the upper levels of the tree.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn get_in_batch_into<Q: QueryShape>(&self, queries: &[Q], out: &mut Vec<Vec<Range<u32>>>) {
+        out.resize_with(queries.len(), Vec::new);
+        out.iter_mut().for_each(Vec::clear);
+
+        if self.data.is_empty() || queries.is_empty() {
+            return;
+        }
+
+        // lists of query indices; a node's list is only needed until both its children are done
+        // which in a DFS means this behaves like a stack
+        let mut active: Vec<u32> = (0..queries.len() as u32).collect();
+
+        let mut dfs_stack = vec![Frame {
+            idx: ROOT_IDX,
+            queries: 0..active.len() as u32,
+        }];
+
+        while let Some(Frame {
+            idx,
+            queries: parent,
+        }) = dfs_stack.pop()
+        {
+            // everything above belongs to subtrees we are done with
+            active.truncate(parent.end as usize);
+
+            let node = unsafe { self.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => {
+                    let range = self.leaf_range(leaf.ptr);
+
+                    for &query_idx in &active[parent.start as usize..] {
+                        if queries[query_idx as usize].contains_point(leaf.point) {
+                            push_merged(&mut out[query_idx as usize], range.clone());
+                        }
+                    }
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    let start = active.len();
+
+                    for i in parent.start as usize..start {
+                        let query_idx = active[i];
+                        if queries[query_idx as usize].intersects_aabb(aabb) {
+                            active.push(query_idx);
+                        }
+                    }
+
+                    if active.len() == start {
+                        continue;
+                    }
+
+                    let intersecting = start as u32..active.len() as u32;
+
+                    dfs_stack.push(Frame {
+                        idx: self.right_child(idx),
+                        queries: intersecting.clone(),
+                    });
+
+                    dfs_stack.push(Frame {
+                        idx: self.left_child(idx),
+                        queries: intersecting,
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,91 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use arrayvec::ArrayVec;
+use glam::I16Vec2;
+
+use super::DFS_STACK_SIZE;
+use crate::node::Expanded;
+use crate::query::{push_merged, Len};
+use crate::{Bvh, Euclidean, Metric, ROOT_IDX};
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Returns the merged ranges of all leaves within the largest of `thresholds` of `centre`,
+    /// grouped by distance.
+    ///
+    /// `thresholds` has to be sorted in increasing order. `result[i]` holds the leaves with a
+    /// Euclidean distance in the half-open interval `(thresholds[i - 1], thresholds[i]]`, and
+    /// `result[0]` those within `thresholds[0]`.
+    pub fn get_in_buckets(&self, centre: I16Vec2, thresholds: &[u16]) -> Vec<Vec<Range<u32>>> {
+        let mut result = Vec::new();
+        self.get_in_buckets_into(centre, thresholds, Euclidean, &mut result);
+        result
+    }
+
+    /// [`Self::get_in_buckets`] where the distance is measured with `metric`, reusing the
+    /// allocations in `out`.
+    ///
+    /// `out` is resized to `thresholds.len()` and every inner `Vec` is cleared first.
+    ///
+    /// A subtree whose minimum and maximum distance (see [`Metric::min_max_distance`]) fall into
+    /// the same bucket is added to it as a single range without visiting its leaves.
+    pub fn get_in_buckets_into<M: Metric>(
+        &self,
+        centre: I16Vec2,
+        thresholds: &[u16],
+        metric: M,
+        out: &mut Vec<Vec<Range<u32>>>,
+    ) {
+        debug_assert!(
+            thresholds.is_sorted(),
+            "thresholds must be sorted, got {thresholds:?}"
+        );
+
+        out.resize_with(thresholds.len(), Vec::new);
+        out.iter_mut().for_each(Vec::clear);
+
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        // the first bucket `dist` fits in, `thresholds.len()` if it is too far away
+        let bucket =
+            |dist: u32| thresholds.partition_point(|&threshold| metric.radius(threshold) < dist);
+
+        let mut dfs_stack: ArrayVec<u32, DFS_STACK_SIZE> = ArrayVec::new();
+        dfs_stack.push(ROOT_IDX);
+
+        while let Some(idx) = dfs_stack.pop() {
+            let node = unsafe { self.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => {
+                    let bucket = bucket(metric.distance(leaf.point, centre));
+
+                    if let Some(out) = out.get_mut(bucket) {
+                        push_merged(out, self.leaf_range(leaf.ptr));
+                    }
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    let (dist_min, dist_max) = metric.min_max_distance(aabb, centre);
+
+                    let closest = bucket(dist_min);
+
+                    if closest == thresholds.len() {
+                        continue;
+                    }
+
+                    if bucket(dist_max) == closest {
+                        push_merged(&mut out[closest], self.subtree_range(idx));
+                        continue;
+                    }
+
+                    // left is popped first so leaves come out in build order
+                    dfs_stack.push(self.right_child(idx));
+                    dfs_stack.push(self.left_child(idx));
+                }
+                None => {}
+            }
+        }
+    }
+}
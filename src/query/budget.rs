@@ -0,0 +1,140 @@
+use std::alloc::Allocator;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::Range;
+
+use bytes::Bytes;
+use glam::I16Vec2;
+
+use crate::node::Expanded;
+use crate::query::{push_merged, Len, MinNode};
+use crate::{Bvh, Euclidean, Metric, QueryShape, ROOT_IDX};
+
+/// The result of [`Bvh::get_in_nearest`].
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Budgeted {
+    /// The ranges of the leaves that fit into the budget, closest first.
+    ///
+    /// Adjacent ranges are merged as long as this keeps the order.
+    pub ranges: Vec<Range<u32>>,
+    /// The number of elements inside the query that did not fit into the budget.
+    pub dropped: u32,
+}
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Returns the ranges of the leaves inside `query` by increasing Euclidean distance from
+    /// `centre`, stopping before the first leaf that would exceed `max_elements`.
+    ///
+    /// See [`Self::get_in_nearest_with`].
+    pub fn get_in_nearest(
+        &self,
+        query: impl QueryShape,
+        centre: I16Vec2,
+        max_elements: u32,
+    ) -> Budgeted {
+        self.get_in_nearest_with(query, centre, max_elements, Euclidean)
+    }
+
+    /// [`Self::get_in_nearest`] where the distance is measured with `metric`.
+    ///
+    /// Leaves are taken whole in a best-first traversal ordered by the distance to `centre`, so
+    /// everything that is cut off is at least as far away as everything that is returned. The
+    /// amount cut off is counted with [`Self::count_in`], which only runs if the budget is hit.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get_in_nearest_with<M: Metric>(
+        &self,
+        query: impl QueryShape,
+        centre: I16Vec2,
+        max_elements: u32,
+        metric: M,
+    ) -> Budgeted {
+        let mut result = Budgeted::default();
+
+        if self.nodes.is_empty() {
+            return result;
+        }
+
+        let mut heap: BinaryHeap<Reverse<MinNode>> = BinaryHeap::new();
+
+        let push = |heap: &mut BinaryHeap<_>, idx: u32| {
+            let node = unsafe { self.get_node(idx) };
+
+            let dist = match node.into_expanded() {
+                Some(Expanded::Aabb(aabb)) if query.intersects_aabb(aabb) => {
+                    metric.min_max_distance(aabb, centre).0
+                }
+                Some(Expanded::Leaf(leaf)) if query.contains_point(leaf.point) => {
+                    metric.distance(leaf.point, centre)
+                }
+                _ => return,
+            };
+
+            heap.push(Reverse(MinNode {
+                dist,
+                expanded: node.into_expanded().expect("checked above"),
+                idx,
+            }));
+        };
+
+        push(&mut heap, ROOT_IDX);
+
+        let mut taken = 0;
+        let mut exceeded = false;
+
+        while let Some(Reverse(context)) = heap.pop() {
+            match context.expanded {
+                Expanded::Leaf(leaf) => {
+                    let range = self.leaf_range(leaf.ptr);
+                    let len = range.end - range.start;
+
+                    if taken + len > max_elements {
+                        exceeded = true;
+                        break;
+                    }
+
+                    taken += len;
+                    push_merged(&mut result.ranges, range);
+                }
+                Expanded::Aabb(..) => {
+                    push(&mut heap, self.left_child(context.idx));
+                    push(&mut heap, self.right_child(context.idx));
+                }
+            }
+        }
+
+        if exceeded {
+            result.dropped = self.count_in(&query) - taken;
+        }
+
+        result
+    }
+}
+
+impl<T, A: Allocator> Bvh<Vec<T>, A> {
+    /// [`Bvh::get_in_nearest`] with a budget of `max_bytes` instead of a number of elements.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn get_in_nearest_bytes(
+        &self,
+        query: impl QueryShape,
+        centre: I16Vec2,
+        max_bytes: usize,
+    ) -> Budgeted {
+        let max_elements = max_bytes.checked_div(size_of::<T>()).unwrap_or(usize::MAX);
+        let max_elements = max_elements.min(u32::MAX as usize) as u32;
+        self.get_in_nearest(query, centre, max_elements)
+    }
+}
+
+impl<A: Allocator> Bvh<Bytes, A> {
+    /// [`Bvh::get_in_nearest`] with a budget of `max_bytes`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn get_in_nearest_bytes(
+        &self,
+        query: impl QueryShape,
+        centre: I16Vec2,
+        max_bytes: usize,
+    ) -> Budgeted {
+        let max_elements = max_bytes.min(u32::MAX as usize) as u32;
+        self.get_in_nearest(query, centre, max_elements)
+    }
+}
@@ -0,0 +1,135 @@
+use std::alloc::Allocator;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::{Deref, Range};
+
+use bytes::Bytes;
+use glam::I16Vec2;
+
+use super::MinNode;
+use crate::node::Expanded;
+use crate::query::Len;
+use crate::{Bvh, Euclidean, Metric, ROOT_IDX};
+
+impl<T, L: Len + Deref<Target = [T]>, A: Allocator> Bvh<L, A> {
+    /// Returns the range of the closest leaf for which `predicate` returns `true`.
+    ///
+    /// `predicate` is called with the point and data of each candidate leaf.
+    pub fn get_closest_where(
+        &self,
+        input: I16Vec2,
+        predicate: impl FnMut(I16Vec2, &[T]) -> bool,
+    ) -> Option<Range<u32>> {
+        self.get_closest_where_with(input, Euclidean, predicate)
+    }
+
+    /// [`Self::get_closest_where`] using `metric` instead of the Euclidean distance.
+    ///
+    /// Leaves are checked as soon as they are reached, so only accepted leaves bound the search.
+    /// The maximum distance of a node cannot be used as a bound like in [`Self::get_closest`]
+    /// because every leaf inside it might get rejected.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn get_closest_where_with<M: Metric>(
+        &self,
+        input: I16Vec2,
+        metric: M,
+        mut predicate: impl FnMut(I16Vec2, &[T]) -> bool,
+    ) -> Option<Range<u32>> {
+        if self.data.is_empty() {
+            return None;
+        }
+
+        let mut accept = |point: I16Vec2, range: &Range<u32>| {
+            predicate(point, &self.data[range.start as usize..range.end as usize])
+        };
+
+        let mut max_distance_to_closest = u32::MAX;
+        let mut heap: BinaryHeap<Reverse<MinNode>> = BinaryHeap::new();
+
+        let node = unsafe { self.get_node(ROOT_IDX) };
+        let expanded = node.into_expanded().expect("root node is always valid");
+
+        if let Expanded::Leaf(leaf) = expanded {
+            let range = self.leaf_range(leaf.ptr);
+            return accept(leaf.point, &range).then_some(range);
+        }
+
+        heap.push(Reverse(MinNode {
+            dist: 0,
+            expanded,
+            idx: ROOT_IDX,
+        }));
+
+        while let Some(Reverse(context)) = heap.pop() {
+            if context.dist > max_distance_to_closest {
+                // an accepted leaf closer than anything left is already in the heap
+                continue;
+            }
+
+            match context.expanded {
+                Expanded::Leaf(leaf) => return Some(self.leaf_range(leaf.ptr)),
+                Expanded::Aabb(..) => {
+                    for idx in [self.left_child(context.idx), self.right_child(context.idx)] {
+                        let node = unsafe { self.get_node(idx) };
+
+                        let Some(expanded) = node.into_expanded() else {
+                            continue;
+                        };
+
+                        let dist = match expanded {
+                            Expanded::Aabb(aabb) => metric.min_max_distance(aabb, input).0,
+                            Expanded::Leaf(leaf) => {
+                                let dist = metric.distance(leaf.point, input);
+
+                                if dist > max_distance_to_closest
+                                    || !accept(leaf.point, &self.leaf_range(leaf.ptr))
+                                {
+                                    continue;
+                                }
+
+                                max_distance_to_closest = dist;
+                                dist
+                            }
+                        };
+
+                        if dist > max_distance_to_closest {
+                            continue;
+                        }
+
+                        heap.push(Reverse(MinNode {
+                            dist,
+                            expanded,
+                            idx,
+                        }));
+                    }
+                }
+            }
+        }
+
+        None
+    }
+}
+
+impl<A: Allocator> Bvh<Bytes, A> {
+    pub fn get_closest_where_slice_bytes(
+        &self,
+        input: I16Vec2,
+        predicate: impl FnMut(I16Vec2, &[u8]) -> bool,
+    ) -> Option<Bytes> {
+        let idx = self.get_closest_where(input, predicate)?;
+        let idx = idx.start as usize..idx.end as usize;
+        Some(self.data.slice(idx))
+    }
+}
+
+impl<T, A: Allocator> Bvh<Vec<T>, A> {
+    pub fn get_closest_where_slice(
+        &self,
+        input: I16Vec2,
+        predicate: impl FnMut(I16Vec2, &[T]) -> bool,
+    ) -> Option<&[T]> {
+        let idx = self.get_closest_where(input, predicate)?;
+        let idx = idx.start as usize..idx.end as usize;
+        Some(&self.data[idx])
+    }
+}
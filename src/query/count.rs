@@ -0,0 +1,77 @@
+use std::alloc::Allocator;
+
+use arrayvec::ArrayVec;
+use bytes::Bytes;
+
+use super::DFS_STACK_SIZE;
+use crate::node::Expanded;
+use crate::query::Len;
+use crate::{Bvh, QueryShape, ROOT_IDX};
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Returns the number of elements inside `query` without collecting any ranges.
+    ///
+    /// `leaves` stores the prefix sum of the elements of every leaf, and the leaves below a node
+    /// are contiguous, so the total of a subtree fully inside `query` is the difference of two
+    /// offsets and its leaves are never visited.
+    pub fn count_in(&self, query: impl QueryShape) -> u32 {
+        let mut count = 0;
+
+        if self.nodes.is_empty() {
+            return count;
+        }
+
+        let mut dfs_stack: ArrayVec<u32, DFS_STACK_SIZE> = ArrayVec::new();
+        dfs_stack.push(ROOT_IDX);
+
+        while let Some(idx) = dfs_stack.pop() {
+            let node = unsafe { self.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => {
+                    if !query.contains_point(leaf.point) {
+                        continue;
+                    }
+
+                    let range = self.leaf_range(leaf.ptr);
+                    count += range.end - range.start;
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    if !query.intersects_aabb(aabb) {
+                        continue;
+                    }
+
+                    if query.contains_aabb(aabb) {
+                        let range = self.subtree_range(idx);
+                        count += range.end - range.start;
+                        continue;
+                    }
+
+                    dfs_stack.push(self.right_child(idx));
+                    dfs_stack.push(self.left_child(idx));
+                }
+                None => {}
+            }
+        }
+
+        count
+    }
+}
+
+impl<T, A: Allocator> Bvh<Vec<T>, A> {
+    /// Returns the size in bytes of all elements inside `query`.
+    ///
+    /// See [`Self::count_in`].
+    pub fn bytes_in(&self, query: impl QueryShape) -> usize {
+        self.count_in(query) as usize * size_of::<T>()
+    }
+}
+
+impl<A: Allocator> Bvh<Bytes, A> {
+    /// Returns the number of bytes inside `query`.
+    ///
+    /// See [`Self::count_in`].
+    pub fn bytes_in(&self, query: impl QueryShape) -> usize {
+        self.count_in(query) as usize
+    }
+}
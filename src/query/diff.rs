@@ -0,0 +1,108 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use arrayvec::ArrayVec;
+use glam::I16Vec2;
+
+use super::DFS_STACK_SIZE;
+use crate::node::Expanded;
+use crate::query::Len;
+use crate::{Bvh, QueryShape, ROOT_IDX};
+
+/// The leaves that changed between two queries, see [`Bvh::get_in_diff`].
+///
+/// Both lists hold the point and range of every leaf in build order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct InDiff {
+    /// Leaves inside the current query but not inside the previous one.
+    pub entered: Vec<(I16Vec2, Range<u32>)>,
+    /// Leaves inside the previous query but not inside the current one.
+    pub left: Vec<(I16Vec2, Range<u32>)>,
+}
+
+impl InDiff {
+    pub fn clear(&mut self) {
+        self.entered.clear();
+        self.left.clear();
+    }
+}
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Returns the leaves that entered and left the query when it moved from `prev` to `cur`.
+    ///
+    /// Both shapes are tested during a single traversal. Subtrees that are inside both shapes
+    /// or outside both shapes are skipped, and subtrees fully inside only one of them are
+    /// reported without testing their leaves.
+    pub fn get_in_diff(&self, prev: impl QueryShape, cur: impl QueryShape) -> InDiff {
+        let mut diff = InDiff::default();
+        self.get_in_diff_into(prev, cur, &mut diff);
+        diff
+    }
+
+    /// [`Self::get_in_diff`] writing into `diff`, which is cleared first.
+    pub fn get_in_diff_into(&self, prev: impl QueryShape, cur: impl QueryShape, diff: &mut InDiff) {
+        diff.clear();
+
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let push_subtree = |out: &mut Vec<_>, idx: u32| {
+            out.extend(
+                self.subtree_leaves(idx)
+                    .map(|ptr| (self.leaf_point(ptr), self.leaf_range(ptr))),
+            );
+        };
+
+        let mut dfs_stack: ArrayVec<u32, DFS_STACK_SIZE> = ArrayVec::new();
+        dfs_stack.push(ROOT_IDX);
+
+        while let Some(idx) = dfs_stack.pop() {
+            let node = unsafe { self.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => {
+                    let in_prev = prev.contains_point(leaf.point);
+                    let in_cur = cur.contains_point(leaf.point);
+
+                    let out = match (in_prev, in_cur) {
+                        (false, true) => &mut diff.entered,
+                        (true, false) => &mut diff.left,
+                        _ => continue,
+                    };
+
+                    out.push((leaf.point, self.leaf_range(leaf.ptr)));
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    let hits_prev = prev.intersects_aabb(aabb);
+                    let hits_cur = cur.intersects_aabb(aabb);
+
+                    if !hits_prev && !hits_cur {
+                        continue;
+                    }
+
+                    let in_prev = hits_prev && prev.contains_aabb(aabb);
+                    let in_cur = hits_cur && cur.contains_aabb(aabb);
+
+                    match (in_prev, in_cur) {
+                        (true, true) => continue,
+                        (false, true) if !hits_prev => {
+                            push_subtree(&mut diff.entered, idx);
+                            continue;
+                        }
+                        (true, false) if !hits_cur => {
+                            push_subtree(&mut diff.left, idx);
+                            continue;
+                        }
+                        _ => {}
+                    }
+
+                    // left is popped first so leaves come out in build order
+                    dfs_stack.push(self.right_child(idx));
+                    dfs_stack.push(self.left_child(idx));
+                }
+                None => {}
+            }
+        }
+    }
+}
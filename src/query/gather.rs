@@ -0,0 +1,130 @@
+use std::alloc::Allocator;
+use std::ops::Deref;
+
+use bytes::{BufMut, BytesMut};
+
+use crate::query::Len;
+use crate::{Bvh, QueryShape};
+
+/// How each leaf is prefixed by [`Bvh::gather_in_framed`].
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub enum Framing {
+    /// No prefix; leaves are copied back to back.
+    #[default]
+    None,
+    /// The length as a big-endian `u32`.
+    U32,
+    /// The length as a Minecraft `VarInt`.
+    VarInt,
+}
+
+impl Framing {
+    /// Writes the prefix for a leaf of `len` bytes, returning the number of bytes written.
+    #[allow(clippy::cast_possible_truncation)]
+    fn put(self, out: &mut BytesMut, len: u32) -> usize {
+        match self {
+            Self::None => 0,
+            Self::U32 => {
+                out.put_u32(len);
+                size_of::<u32>()
+            }
+            Self::VarInt => {
+                let mut value = len;
+                let mut written = 0;
+
+                loop {
+                    written += 1;
+
+                    if value & !0x7F == 0 {
+                        out.put_u8(value as u8);
+                        return written;
+                    }
+
+                    out.put_u8((value as u8 & 0x7F) | 0x80);
+                    value >>= 7;
+                }
+            }
+        }
+    }
+}
+
+impl<L: Len + Deref<Target = [u8]>, A: Allocator> Bvh<L, A> {
+    /// Copies all bytes inside `query` into `out` in build order.
+    ///
+    /// Returns the number of bytes appended to `out`.
+    pub fn gather_in(&self, query: impl QueryShape, out: &mut BytesMut) -> usize {
+        let start = out.len();
+
+        for range in self.get_in_iter(query) {
+            out.extend_from_slice(&self.data[range.start as usize..range.end as usize]);
+        }
+
+        out.len() - start
+    }
+
+    /// [`Self::gather_in`] where the data of every leaf is prefixed by its length as given by
+    /// `framing`, so the receiver can split `out` per leaf again.
+    ///
+    /// Leaves without any data are skipped. Returns the number of bytes appended to `out`,
+    /// including the prefixes.
+    pub fn gather_in_framed(
+        &self,
+        query: impl QueryShape,
+        out: &mut BytesMut,
+        framing: Framing,
+    ) -> usize {
+        if framing == Framing::None {
+            return self.gather_in(query, out);
+        }
+
+        let mut written = 0;
+
+        for (_, range) in self.get_in_leaf_ranges(query) {
+            if range.is_empty() {
+                continue;
+            }
+
+            written += framing.put(out, range.end - range.start);
+
+            let data = &self.data[range.start as usize..range.end as usize];
+            out.extend_from_slice(data);
+            written += data.len();
+        }
+
+        written
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var_int(len: u32) -> Vec<u8> {
+        let mut out = BytesMut::new();
+        let written = Framing::VarInt.put(&mut out, len);
+        assert_eq!(written, out.len());
+        out.to_vec()
+    }
+
+    #[test]
+    fn test_var_int() {
+        assert_eq!(var_int(0), [0x00]);
+        assert_eq!(var_int(1), [0x01]);
+        assert_eq!(var_int(127), [0x7F]);
+        assert_eq!(var_int(128), [0x80, 0x01]);
+        assert_eq!(var_int(255), [0xFF, 0x01]);
+        assert_eq!(var_int(25_565), [0xDD, 0xC7, 0x01]);
+        assert_eq!(var_int(2_097_151), [0xFF, 0xFF, 0x7F]);
+        assert_eq!(var_int(u32::MAX), [0xFF, 0xFF, 0xFF, 0xFF, 0x0F]);
+    }
+
+    #[test]
+    fn test_u32_prefix() {
+        let mut out = BytesMut::new();
+        assert_eq!(Framing::U32.put(&mut out, 0x0102_0304), 4);
+        assert_eq!(&out[..], [1, 2, 3, 4]);
+
+        assert_eq!(Framing::None.put(&mut out, 5), 0);
+        assert_eq!(out.len(), 4);
+    }
+}
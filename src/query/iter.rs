@@ -0,0 +1,106 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use arrayvec::ArrayVec;
+
+use super::DFS_STACK_SIZE;
+use crate::node::Expanded;
+use crate::{Bvh, QueryShape, ROOT_IDX};
+
+/// Lazy version of [`Bvh::get_in`].
+///
+/// Leaves are visited in the same DFS order as the tree was built in, so adjacent leaves are
+/// merged into a single range before being yielded. Subtrees fully inside the query are yielded
+/// as one range without visiting their leaves.
+pub struct InIter<'a, L, A: Allocator, Q> {
+    bvh: &'a Bvh<L, A>,
+    query: Q,
+    dfs_stack: ArrayVec<u32, DFS_STACK_SIZE>,
+    pending: Option<Range<u32>>,
+}
+
+impl<'a, L, A: Allocator, Q: QueryShape> InIter<'a, L, A, Q> {
+    pub fn new(bvh: &'a Bvh<L, A>, query: Q) -> Self {
+        Self::starting_at(bvh, query, ROOT_IDX)
+    }
+
+    /// Only visits the subtree below the node at `idx`.
+    pub fn starting_at(bvh: &'a Bvh<L, A>, query: Q, idx: u32) -> Self {
+        let mut dfs_stack = ArrayVec::new();
+
+        if !bvh.nodes.is_empty() {
+            dfs_stack.push(idx);
+        }
+
+        Self {
+            bvh,
+            query,
+            dfs_stack,
+            pending: None,
+        }
+    }
+}
+
+impl<L, A: Allocator, Q: QueryShape> InIter<'_, L, A, Q> {
+    /// Merges `range` into the pending range, returning the previous one if they are not adjacent.
+    fn push(&mut self, range: Range<u32>) -> Option<Range<u32>> {
+        if range.is_empty() {
+            return None;
+        }
+
+        match &mut self.pending {
+            Some(pending) if pending.end == range.start => {
+                // combine
+                pending.end = range.end;
+                None
+            }
+            pending => pending.replace(range),
+        }
+    }
+}
+
+impl<L, A: Allocator, Q: QueryShape> Iterator for InIter<'_, L, A, Q> {
+    type Item = Range<u32>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while let Some(idx) = self.dfs_stack.pop() {
+            let node = unsafe { self.bvh.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => {
+                    if !self.query.contains_point(leaf.point) {
+                        continue;
+                    }
+
+                    let range = self.bvh.leaf_range(leaf.ptr);
+
+                    if let Some(previous) = self.push(range) {
+                        return Some(previous);
+                    }
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    if !self.query.intersects_aabb(aabb) {
+                        continue;
+                    }
+
+                    if self.query.contains_aabb(aabb) {
+                        let range = self.bvh.subtree_range(idx);
+
+                        if let Some(previous) = self.push(range) {
+                            return Some(previous);
+                        }
+
+                        continue;
+                    }
+
+                    // left is popped first so leaves come out in build order
+                    self.dfs_stack.push(self.bvh.right_child(idx));
+                    self.dfs_stack.push(self.bvh.left_child(idx));
+                }
+                None => {}
+            }
+        }
+
+        self.pending.take()
+    }
+}
@@ -0,0 +1,301 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use arrayvec::ArrayVec;
+use glam::{I16Vec2, IVec2, U16Vec2};
+
+use super::DFS_STACK_SIZE;
+use crate::node::Expanded;
+use crate::query::{push_merged, InIter, Len};
+use crate::{Aabb, Bvh, Euclidean, Metric, QueryShape, ROOT_IDX};
+
+/// The bounds of a node, where a leaf is a single point.
+const fn bounds(expanded: Expanded) -> Aabb {
+    match expanded {
+        Expanded::Aabb(aabb) => aabb,
+        Expanded::Leaf(leaf) => Aabb::point(leaf.point),
+    }
+}
+
+/// `aabb` grown by `half_extent` on every side, i.e. the union of the query boxes of its points.
+#[allow(clippy::cast_possible_truncation)]
+fn grow(aabb: Aabb, half_extent: IVec2) -> Aabb {
+    let min = (aabb.min.as_ivec2() - half_extent).max(IVec2::splat(i16::MIN.into()));
+    let max = (aabb.max.as_ivec2() + half_extent).min(IVec2::splat(i16::MAX.into()));
+    Aabb::new(min.as_i16vec2(), max.as_i16vec2())
+}
+
+/// The intersection of the query boxes of all points in `aabb`, if there is one.
+#[allow(clippy::cast_possible_truncation)]
+fn common(aabb: Aabb, half_extent: IVec2) -> Option<Aabb> {
+    let min = aabb.max.as_ivec2() - half_extent;
+    let max = aabb.min.as_ivec2() + half_extent;
+
+    if min.cmpgt(max).any() {
+        return None;
+    }
+
+    let min = min.max(IVec2::splat(i16::MIN.into()));
+    let max = max.min(IVec2::splat(i16::MAX.into()));
+    Some(Aabb::new(min.as_i16vec2(), max.as_i16vec2()))
+}
+
+#[derive(Debug, Copy, Clone)]
+struct Candidate {
+    /// a node of the other tree
+    idx: u32,
+    /// whether the node is inside the query box of every leaf below the current node
+    contained: bool,
+}
+
+#[derive(Debug, Clone)]
+struct JoinFrame {
+    idx: u32,
+    /// the candidates of the parent of `idx` (a range into the frontier stack)
+    candidates: Range<u32>,
+}
+
+/// Pushes the nodes below `idx` that are near a node of the first tree onto `frontier` in DFS
+/// order.
+///
+/// `union` and `common` are the union and intersection of the query boxes of that node. Nodes
+/// are split until they are fully inside `common` or no larger than `size`.
+fn refine<L, A: Allocator>(
+    bvh: &Bvh<L, A>,
+    idx: u32,
+    union: Aabb,
+    common: Option<Aabb>,
+    size: u32,
+    frontier: &mut Vec<Candidate>,
+) {
+    let mut dfs_stack: ArrayVec<u32, DFS_STACK_SIZE> = ArrayVec::new();
+    dfs_stack.push(idx);
+
+    while let Some(idx) = dfs_stack.pop() {
+        let node = unsafe { bvh.get_node(idx) };
+
+        let Some(expanded) = node.into_expanded() else {
+            continue;
+        };
+
+        let bounds = bounds(expanded);
+
+        if !union.intersects(bounds) {
+            continue;
+        }
+
+        let contained = common.is_some_and(|common| common.contains_aabb(bounds));
+
+        match expanded {
+            Expanded::Aabb(aabb) if !contained && aabb.half_perimeter() > size => {
+                dfs_stack.push(bvh.right_child(idx));
+                dfs_stack.push(bvh.left_child(idx));
+            }
+            _ => frontier.push(Candidate { idx, contained }),
+        }
+    }
+}
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Calls `f` with every pair of leaves within Euclidean distance `distance` of each other.
+    ///
+    /// See [`Self::self_join_within_with`].
+    pub fn self_join_within(
+        &self,
+        distance: u16,
+        f: impl FnMut((I16Vec2, Range<u32>), (I16Vec2, Range<u32>)),
+    ) {
+        self.self_join_within_with(distance, Euclidean, f);
+    }
+
+    /// Calls `f` with the point and range of every pair of leaves within `distance` of each other
+    /// as measured by `metric`.
+    ///
+    /// This is a dual-tree traversal over pairs of nodes starting at `(root, root)`. Pairs whose
+    /// bounds are further apart than `distance` are pruned, and a node paired with itself only
+    /// recurses into `(left, left)`, `(right, right)` and `(left, right)`, so every pair is
+    /// reported exactly once with the first leaf before the second in build order.
+    pub fn self_join_within_with<M: Metric>(
+        &self,
+        distance: u16,
+        metric: M,
+        mut f: impl FnMut((I16Vec2, Range<u32>), (I16Vec2, Range<u32>)),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let radius = metric.radius(distance);
+
+        let mut stack = vec![(ROOT_IDX, ROOT_IDX)];
+
+        while let Some((a, b)) = stack.pop() {
+            let node_a = unsafe { self.get_node(a) };
+            let node_b = unsafe { self.get_node(b) };
+
+            let (Some(node_a), Some(node_b)) = (node_a.into_expanded(), node_b.into_expanded())
+            else {
+                continue;
+            };
+
+            if a == b {
+                if let Expanded::Aabb(..) = node_a {
+                    let left = self.left_child(a);
+                    let right = self.right_child(a);
+
+                    stack.push((left, right));
+                    stack.push((right, right));
+                    stack.push((left, left));
+                }
+
+                continue;
+            }
+
+            let bounds_a = bounds(node_a);
+            let bounds_b = bounds(node_b);
+
+            if metric.norm(bounds_a.gap_lens(bounds_b)) > radius {
+                continue;
+            }
+
+            match (node_a, node_b) {
+                (Expanded::Leaf(leaf_a), Expanded::Leaf(leaf_b)) => {
+                    f(
+                        (leaf_a.point, self.leaf_range(leaf_a.ptr)),
+                        (leaf_b.point, self.leaf_range(leaf_b.ptr)),
+                    );
+                }
+                (Expanded::Aabb(aabb_a), Expanded::Aabb(aabb_b))
+                    if aabb_a.half_perimeter() < aabb_b.half_perimeter() =>
+                {
+                    // split the larger node
+                    stack.push((a, self.right_child(b)));
+                    stack.push((a, self.left_child(b)));
+                }
+                (Expanded::Aabb(..), _) => {
+                    stack.push((self.right_child(a), b));
+                    stack.push((self.left_child(a), b));
+                }
+                (Expanded::Leaf(..), Expanded::Aabb(..)) => {
+                    stack.push((a, self.right_child(b)));
+                    stack.push((a, self.left_child(b)));
+                }
+            }
+        }
+    }
+
+    /// Calls `f` with the point and range of every leaf of `self` in build order, together with
+    /// the merged ranges of `other` inside the box of `half_extent` around that leaf.
+    ///
+    /// Both trees are traversed together. Each node of `self` keeps a frontier of nodes of
+    /// `other` near it, which its children narrow down further, so leaves in the same region share
+    /// the node tests of their ancestors. Nodes of `other` inside the query box of every leaf
+    /// below a node are emitted as a whole.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn join_in<L2: Len, A2: Allocator>(
+        &self,
+        other: &Bvh<L2, A2>,
+        half_extent: U16Vec2,
+        mut f: impl FnMut((I16Vec2, Range<u32>), &[Range<u32>]),
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let half_extent = half_extent.as_ivec2();
+
+        let mut ranges = Vec::new();
+
+        // like the active queries of `get_in_batch_into`, this behaves like a stack
+        let mut frontier = Vec::new();
+
+        if !other.nodes.is_empty() {
+            frontier.push(Candidate {
+                idx: ROOT_IDX,
+                contained: false,
+            });
+        }
+
+        let mut dfs_stack = vec![JoinFrame {
+            idx: ROOT_IDX,
+            candidates: 0..frontier.len() as u32,
+        }];
+
+        while let Some(JoinFrame {
+            idx,
+            candidates: parent,
+        }) = dfs_stack.pop()
+        {
+            // everything above belongs to subtrees we are done with
+            frontier.truncate(parent.end as usize);
+
+            let node = unsafe { self.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) => {
+                    let query = grow(Aabb::point(leaf.point), half_extent);
+
+                    ranges.clear();
+
+                    for candidate in &frontier[parent.start as usize..] {
+                        if candidate.contained {
+                            push_merged(&mut ranges, other.subtree_range(candidate.idx));
+                            continue;
+                        }
+
+                        for range in InIter::starting_at(other, query, candidate.idx) {
+                            push_merged(&mut ranges, range);
+                        }
+                    }
+
+                    f((leaf.point, self.leaf_range(leaf.ptr)), &ranges);
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    let start = frontier.len();
+
+                    let union = grow(aabb, half_extent);
+                    let common = common(aabb, half_extent);
+
+                    for i in parent.start as usize..start {
+                        let candidate = frontier[i];
+
+                        if candidate.contained {
+                            frontier.push(candidate);
+                        } else {
+                            refine(
+                                other,
+                                candidate.idx,
+                                union,
+                                common,
+                                aabb.half_perimeter(),
+                                &mut frontier,
+                            );
+                        }
+                    }
+
+                    if frontier.len() == start {
+                        // nothing of `other` is near any leaf below
+                        for ptr in self.subtree_leaves(idx) {
+                            f((self.leaf_point(ptr), self.leaf_range(ptr)), &[]);
+                        }
+
+                        continue;
+                    }
+
+                    let candidates = start as u32..frontier.len() as u32;
+
+                    dfs_stack.push(JoinFrame {
+                        idx: self.right_child(idx),
+                        candidates: candidates.clone(),
+                    });
+
+                    dfs_stack.push(JoinFrame {
+                        idx: self.left_child(idx),
+                        candidates,
+                    });
+                }
+                None => {}
+            }
+        }
+    }
+}
@@ -0,0 +1,138 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use arrayvec::ArrayVec;
+use bytes::Bytes;
+use glam::I16Vec2;
+
+use super::DFS_STACK_SIZE;
+use crate::node::Expanded;
+use crate::query::Len;
+use crate::{Bvh, QueryShape, ROOT_IDX};
+
+/// Lazy per-leaf version of [`Bvh::get_in_iter`].
+///
+/// Leaves are yielded one by one in build order and adjacent ranges are never merged.
+/// The leaves of a subtree fully inside the query are yielded without testing their points.
+pub struct LeafIter<'a, L, A: Allocator, Q> {
+    bvh: &'a Bvh<L, A>,
+    query: Q,
+    dfs_stack: ArrayVec<u32, DFS_STACK_SIZE>,
+    /// Leaves of the last subtree that was fully inside `query`.
+    contained: Range<u32>,
+}
+
+impl<'a, L, A: Allocator, Q: QueryShape> LeafIter<'a, L, A, Q> {
+    pub fn new(bvh: &'a Bvh<L, A>, query: Q) -> Self {
+        let mut dfs_stack = ArrayVec::new();
+
+        if !bvh.nodes.is_empty() {
+            dfs_stack.push(ROOT_IDX);
+        }
+
+        Self {
+            bvh,
+            query,
+            dfs_stack,
+            contained: 0..0,
+        }
+    }
+}
+
+impl<L, A: Allocator, Q: QueryShape> Iterator for LeafIter<'_, L, A, Q> {
+    type Item = (I16Vec2, Range<u32>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(ptr) = self.contained.next() {
+                return Some((self.bvh.leaf_point(ptr), self.bvh.leaf_range(ptr)));
+            }
+
+            let idx = self.dfs_stack.pop()?;
+            let node = unsafe { self.bvh.get_node(idx) };
+
+            match node.into_expanded() {
+                Some(Expanded::Leaf(leaf)) if self.query.contains_point(leaf.point) => {
+                    return Some((leaf.point, self.bvh.leaf_range(leaf.ptr)));
+                }
+                Some(Expanded::Aabb(aabb)) => {
+                    if !self.query.intersects_aabb(aabb) {
+                        continue;
+                    }
+
+                    if self.query.contains_aabb(aabb) {
+                        self.contained = self.bvh.subtree_leaves(idx);
+                        continue;
+                    }
+
+                    // left is popped first so leaves come out in build order
+                    self.dfs_stack.push(self.bvh.right_child(idx));
+                    self.dfs_stack.push(self.bvh.left_child(idx));
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Lazily yields the point and range of every leaf inside `query` in build order.
+    ///
+    /// Unlike [`Self::get_in_iter`] adjacent leaves are not merged and leaves without any data
+    /// are still yielded.
+    pub fn get_in_leaf_ranges<'a>(
+        &'a self,
+        query: impl QueryShape + 'a,
+    ) -> impl Iterator<Item = (I16Vec2, Range<u32>)> + 'a {
+        LeafIter::new(self, query)
+    }
+
+    /// Yields the point and range of every leaf in build order.
+    pub fn iter_leaf_ranges(&self) -> impl Iterator<Item = (I16Vec2, Range<u32>)> + '_ {
+        (0..self.leaf_count()).map(|ptr| (self.leaf_point(ptr), self.leaf_range(ptr)))
+    }
+}
+
+impl<T, A: Allocator> Bvh<Vec<T>, A> {
+    /// Lazily yields the point and data of every leaf inside `query` in build order.
+    ///
+    /// See [`Self::get_in_leaf_ranges`].
+    pub fn get_in_leaves<'a>(
+        &'a self,
+        query: impl QueryShape + 'a,
+    ) -> impl Iterator<Item = (I16Vec2, &'a [T])> + 'a {
+        self.get_in_leaf_ranges(query)
+            .map(|(point, range)| (point, &self.data[range.start as usize..range.end as usize]))
+    }
+
+    /// Yields the point and data of every leaf in build order.
+    pub fn iter_leaves(&self) -> impl Iterator<Item = (I16Vec2, &[T])> + '_ {
+        self.iter_leaf_ranges()
+            .map(|(point, range)| (point, &self.data[range.start as usize..range.end as usize]))
+    }
+}
+
+impl<A: Allocator> Bvh<Bytes, A> {
+    /// [`Bvh::get_in_leaves`] for [`Bytes`].
+    pub fn get_in_leaves_bytes<'a>(
+        &'a self,
+        query: impl QueryShape + 'a,
+    ) -> impl Iterator<Item = (I16Vec2, Bytes)> + 'a {
+        self.get_in_leaf_ranges(query).map(|(point, range)| {
+            (
+                point,
+                self.data.slice(range.start as usize..range.end as usize),
+            )
+        })
+    }
+
+    /// [`Bvh::iter_leaves`] for [`Bytes`].
+    pub fn iter_leaves_bytes(&self) -> impl Iterator<Item = (I16Vec2, Bytes)> + '_ {
+        self.iter_leaf_ranges().map(|(point, range)| {
+            (
+                point,
+                self.data.slice(range.start as usize..range.end as usize),
+            )
+        })
+    }
+}
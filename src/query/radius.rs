@@ -0,0 +1,28 @@
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use glam::I16Vec2;
+
+use crate::query::Len;
+use crate::{Bvh, Circle, Euclidean, Metric};
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Returns the merged ranges of all leaves within Euclidean distance `radius` of `center`.
+    ///
+    /// Subtrees that are entirely inside the circle are emitted as a single range without
+    /// visiting their leaves.
+    pub fn get_within_radius(&self, center: I16Vec2, radius: u16) -> Vec<Range<u32>> {
+        self.get_within_radius_with(center, radius, Euclidean)
+    }
+
+    /// [`Self::get_within_radius`] where the distance is measured with `metric`.
+    pub fn get_within_radius_with<M: Metric>(
+        &self,
+        center: I16Vec2,
+        radius: u16,
+        metric: M,
+    ) -> Vec<Range<u32>> {
+        self.get_in_iter(Circle::with_metric(center, radius, metric))
+            .collect()
+    }
+}
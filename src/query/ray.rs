@@ -0,0 +1,135 @@
+use std::alloc::Allocator;
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+use std::ops::{ControlFlow, Range};
+
+use glam::{I16Vec2, Vec2};
+
+use crate::aabb::Aabb;
+use crate::node::Expanded;
+use crate::query::Len;
+use crate::{Bvh, ROOT_IDX};
+
+/// A leaf hit by [`Bvh::raycast`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct RayHit {
+    /// The ray parameter at which the leaf's cell is entered.
+    pub t: f32,
+    pub point: I16Vec2,
+    pub range: Range<u32>,
+}
+
+#[derive(Debug, Copy, Clone)]
+struct RayNode {
+    t: f32,
+    expanded: Expanded,
+    idx: u32,
+}
+
+impl PartialEq for RayNode {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
+    }
+}
+
+impl Eq for RayNode {}
+
+impl Ord for RayNode {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        self.t.total_cmp(&other.t)
+    }
+}
+
+impl PartialOrd for RayNode {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<L: Len, A: Allocator> Bvh<L, A> {
+    /// Returns every leaf whose cell is crossed by `origin + t * direction` for `t` in `0..=max_t`,
+    /// ordered by `t`.
+    ///
+    /// Every leaf covers the unit cell `point..point + 1`.
+    pub fn raycast(&self, origin: Vec2, direction: Vec2, max_t: f32) -> Vec<RayHit> {
+        let mut result = Vec::new();
+
+        self.raycast_each(origin, direction, max_t, |hit| {
+            result.push(hit);
+            ControlFlow::Continue(())
+        });
+
+        result
+    }
+
+    /// Returns every leaf whose cell is crossed by the segment from `a` to `b`, ordered from `a`
+    /// to `b`. The `t` of every hit is in `0..=1`.
+    pub fn segment(&self, a: Vec2, b: Vec2) -> Vec<RayHit> {
+        self.raycast(a, b - a, 1.0)
+    }
+
+    /// Calls `f` for every hit in the same order as [`Self::raycast`] until it returns
+    /// [`ControlFlow::Break`].
+    ///
+    /// Nodes are expanded best-first by their entry `t`, so stopping early (e.g. on the first
+    /// solid hit) skips the rest of the tree.
+    #[allow(clippy::missing_panics_doc)]
+    pub fn raycast_each(
+        &self,
+        origin: Vec2,
+        direction: Vec2,
+        max_t: f32,
+        mut f: impl FnMut(RayHit) -> ControlFlow<()>,
+    ) {
+        if self.nodes.is_empty() {
+            return;
+        }
+
+        let entry = |expanded: Expanded| match expanded {
+            Expanded::Aabb(aabb) => aabb.ray_entry(origin, direction, max_t),
+            Expanded::Leaf(leaf) => Aabb::point(leaf.point).ray_entry(origin, direction, max_t),
+        };
+
+        let mut heap: BinaryHeap<Reverse<RayNode>> = BinaryHeap::new();
+
+        let node = unsafe { self.get_node(ROOT_IDX) };
+        let expanded = node.into_expanded().expect("root node is always valid");
+
+        if let Some(t) = entry(expanded) {
+            heap.push(Reverse(RayNode {
+                t,
+                expanded,
+                idx: ROOT_IDX,
+            }));
+        }
+
+        while let Some(Reverse(context)) = heap.pop() {
+            match context.expanded {
+                Expanded::Leaf(leaf) => {
+                    let hit = RayHit {
+                        t: context.t,
+                        point: leaf.point,
+                        range: self.leaf_range(leaf.ptr),
+                    };
+
+                    if f(hit).is_break() {
+                        return;
+                    }
+                }
+                Expanded::Aabb(..) => {
+                    for idx in [self.left_child(context.idx), self.right_child(context.idx)] {
+                        let node = unsafe { self.get_node(idx) };
+
+                        let Some(expanded) = node.into_expanded() else {
+                            continue;
+                        };
+
+                        if let Some(t) = entry(expanded) {
+                            heap.push(Reverse(RayNode { t, expanded, idx }));
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
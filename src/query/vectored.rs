@@ -0,0 +1,125 @@
+use std::alloc::Allocator;
+use std::io::{self, ErrorKind, IoSlice, Write};
+
+use bytes::Bytes;
+
+use crate::{Bvh, QueryShape};
+
+impl<A: Allocator> Bvh<Bytes, A> {
+    /// Appends one [`IoSlice`] for each merged range inside `query` to `out`.
+    ///
+    /// The slices borrow `self` directly, so `out` can be handed to `writev` or `io_uring` as is.
+    pub fn get_in_io_slices<'a>(&'a self, query: impl QueryShape + 'a, out: &mut Vec<IoSlice<'a>>) {
+        out.extend(
+            self.get_in_iter(query)
+                .map(|range| IoSlice::new(&self.data[range.start as usize..range.end as usize])),
+        );
+    }
+
+    /// Writes all bytes inside `query` to `writer` using vectored writes.
+    ///
+    /// Returns the number of bytes written. See [`write_all_vectored`].
+    ///
+    /// # Errors
+    /// If `writer` returns an error other than [`ErrorKind::Interrupted`] or stops accepting data.
+    pub fn write_in(&self, query: impl QueryShape, writer: &mut impl Write) -> io::Result<usize> {
+        let mut slices = Vec::new();
+        self.get_in_io_slices(query, &mut slices);
+        write_all_vectored(writer, &mut slices)
+    }
+}
+
+/// Writes every byte of `slices` to `writer`, issuing as few `write_vectored` calls as possible.
+///
+/// Partial writes are resumed from where they stopped and interrupted writes are retried.
+/// `slices` is left in an unspecified state. Returns the number of bytes written.
+///
+/// # Errors
+/// If `writer` returns an error other than [`ErrorKind::Interrupted`] or stops accepting data.
+pub fn write_all_vectored(
+    writer: &mut impl Write,
+    mut slices: &mut [IoSlice<'_>],
+) -> io::Result<usize> {
+    let total = slices.iter().map(|slice| slice.len()).sum();
+
+    // skip leading empty slices so an empty result never calls `writer`
+    IoSlice::advance_slices(&mut slices, 0);
+
+    while !slices.is_empty() {
+        match writer.write_vectored(slices) {
+            Ok(0) => {
+                return Err(io::Error::new(
+                    ErrorKind::WriteZero,
+                    "failed to write whole buffer",
+                ));
+            }
+            Ok(written) => IoSlice::advance_slices(&mut slices, written),
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(total)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Accepts at most `limit` bytes of the first non-empty slice per call and is interrupted
+    /// every other call.
+    struct Trickle {
+        out: Vec<u8>,
+        limit: usize,
+        interrupt: bool,
+    }
+
+    impl Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.interrupt = !self.interrupt;
+
+            if !self.interrupt {
+                return Err(ErrorKind::Interrupted.into());
+            }
+
+            let len = buf.len().min(self.limit);
+            self.out.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_write_all_vectored_partial() {
+        let mut writer = Trickle {
+            out: Vec::new(),
+            limit: 3,
+            interrupt: false,
+        };
+
+        let mut slices = [
+            IoSlice::new(&[]),
+            IoSlice::new(&[1, 2, 3, 4, 5]),
+            IoSlice::new(&[]),
+            IoSlice::new(&[6, 7]),
+            IoSlice::new(&[8, 9, 10, 11]),
+        ];
+
+        let written = write_all_vectored(&mut writer, &mut slices).unwrap();
+
+        assert_eq!(written, 11);
+        assert_eq!(writer.out, (1..=11).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn test_write_all_vectored_write_zero() {
+        let mut slices = [IoSlice::new(&[1, 2, 3])];
+        let mut full: &mut [u8] = &mut [0; 2];
+
+        let err = write_all_vectored(&mut full, &mut slices).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WriteZero);
+    }
+}
@@ -0,0 +1,367 @@
+//! Building a tree from the input of the previous build.
+use std::alloc::Allocator;
+
+use glam::I16Vec2;
+
+use crate::node::{Leaf, Node};
+use crate::sealed::PointWithData;
+use crate::{
+    build_levels, into_cells, leaf_nodes, parent, process_input, sort, Bvh, Curve, Hilbert,
+};
+
+impl<T, A: Allocator + Clone> Bvh<Vec<T, A>, A> {
+    /// Updates `self` to a tree over `input`, only redoing the work for the elements that
+    /// changed since the previous build. The result is identical to [`Bvh::build_in`].
+    ///
+    /// `input` has to be the slice the previous build sorted, and `changed` the indices of every
+    /// element whose point or data changed or that was replaced, e.g. by [`Vec::swap_remove`].
+    /// Indices past the end of the previous input do not have to be listed, so elements can be
+    /// pushed without them, and elements may be removed from the end. Indices may be in any
+    /// order, repeat, or be out of bounds.
+    ///
+    /// - Only the changed elements are sorted into place, by swapping them past their
+    ///   neighbours (see [`Self::build_in`] for the order).
+    /// - Only the data of the leaves between the first and last element that moved is copied
+    ///   again.
+    /// - Only the inner nodes above leaves that changed are recomputed, like in
+    ///   [`Self::refit`].
+    ///
+    /// So the time is proportional to the number of changed elements and how far they move,
+    /// except that:
+    ///
+    /// - If the data of the changed leaves got longer or shorter, the data and leaves after
+    ///   them are shifted in memory.
+    /// - If the number of distinct points changed, every leaf after the first changed one moves
+    ///   to a new index, so the nodes are copied and those leaves and their ancestors are
+    ///   written again. If the number crossed a power of two, all inner nodes are built again.
+    /// - If elements moved too far, or the tree was built with [`Self::build_sah_in`], this is
+    ///   [`Self::build_in`].
+    ///
+    /// Returns the new [`Self::cost`].
+    pub fn rebuild<I>(&mut self, input: &mut [I], changed: &[usize], context: I::Context<'_>) -> u64
+    where
+        I: PointWithData<Unit = T>,
+        T: Copy + 'static,
+    {
+        self.rebuild_with_curve(input, changed, Hilbert, context)
+    }
+
+    /// [`Self::rebuild`] for a tree built with [`Self::build_in_with_curve`]. The result is
+    /// identical to building with `curve`.
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn rebuild_with_curve<I, C>(
+        &mut self,
+        input: &mut [I],
+        changed: &[usize],
+        curve: C,
+        context: I::Context<'_>,
+    ) -> u64
+    where
+        I: PointWithData<Unit = T>,
+        T: Copy + 'static,
+        C: Curve,
+    {
+        let alloc = self.leaves.allocator().clone();
+        let previous_len = self.input_len as usize;
+
+        if input.is_empty() || self.leaf_count() == 0 || !self.is_implicit() {
+            *self = Self::build_in_with_curve(input, alloc, curve, context);
+            return self.cost();
+        }
+
+        // everything past the previous end is new
+        let mut dirty = Vec::with_capacity_in(changed.len(), alloc.clone());
+        dirty.extend(changed.iter().copied().filter(|&i| i < input.len()));
+        dirty.extend(previous_len..input.len());
+        dirty.sort_unstable();
+        dirty.dedup();
+
+        let Some(mut touched) = sort::sort_changed_by_curve(input, &dirty, curve, alloc.clone())
+        else {
+            *self = Self::build_in_with_curve(input, alloc, curve, context);
+            return self.cost();
+        };
+
+        if input.len() < previous_len {
+            // the leaves of the removed elements go as well
+            touched.start = touched.start.min(input.len());
+            touched.end = input.len();
+        } else if touched.is_empty() {
+            return self.cost();
+        }
+
+        // widen to whole leaves: from the leaf of the last untouched element in front, up to the
+        // first leaf behind that is not touched
+        let (start, first_leaf) = touched.start.checked_sub(1).map_or((0, 0), |last| {
+            let point = input[last].point();
+            let start = input[..last]
+                .iter()
+                .rposition(|elem| elem.point() != point)
+                .map_or(0, |i| i + 1);
+
+            (start, self.find_leaf(point, curve))
+        });
+
+        let (end, end_leaf) = (touched.end + 1..input.len())
+            .find(|&i| input[i - 1].point() != input[i].point())
+            .map_or_else(
+                || (input.len(), self.leaf_count()),
+                |i| (i, self.find_leaf(input[i].point(), curve)),
+            );
+
+        let (data, leaves, points) = process_input(&input[start..end], alloc.clone(), context);
+
+        let dirty = self.replace_leaf_nodes(first_leaf..end_leaf, &points, alloc);
+
+        // the offsets behind the replaced data shift by the difference in length
+        let units = self.leaves[first_leaf as usize].element_index
+            ..self.leaves[end_leaf as usize].element_index;
+        let new_units = data.len() as u32;
+
+        self.data
+            .splice(units.start as usize..units.end as usize, data);
+
+        let offset = |leaf: &Leaf| Leaf::new(units.start + leaf.element_index);
+        self.leaves.splice(
+            first_leaf as usize..end_leaf as usize,
+            leaves.iter().map(offset),
+        );
+
+        if new_units != units.end - units.start {
+            for leaf in &mut self.leaves[first_leaf as usize + leaves.len()..] {
+                leaf.element_index = leaf.element_index - units.end + units.start + new_units;
+            }
+        }
+
+        if let Some(dirty) = dirty {
+            self.refit_dirty(dirty);
+        }
+
+        self.input_len = input.len() as u32;
+        self.cost()
+    }
+
+    /// Replaces the leaf nodes in `replaced` with leaves at `points`, and returns the inner
+    /// nodes that have to be refit, or `None` if all of them were built again.
+    ///
+    /// Has to be called before the leaves are replaced, as it reads the leaf count.
+    fn replace_leaf_nodes(
+        &mut self,
+        replaced: std::ops::Range<u32>,
+        points: &[I16Vec2],
+        alloc: A,
+    ) -> Option<Vec<u32, A>> {
+        let previous_count = self.leaf_count();
+        let leaves_next_pow2 = self.leaves_next_pow2();
+
+        #[allow(clippy::cast_possible_truncation)]
+        let count = previous_count - replaced.len() as u32 + points.len() as u32;
+
+        if count.next_power_of_two() != previous_count.next_power_of_two() {
+            // the leaves are on a different level, start over
+            let mut all = Vec::with_capacity_in(count as usize, alloc.clone());
+            all.extend((0..replaced.start).map(|ptr| self.leaf_point(ptr)));
+            all.extend_from_slice(points);
+            all.extend((replaced.end..previous_count).map(|ptr| self.leaf_point(ptr)));
+
+            let mut nodes = leaf_nodes(&all, alloc);
+            build_levels(&mut nodes, all.len());
+
+            self.nodes = into_cells(nodes);
+            self.cost.set(None);
+            return None;
+        }
+
+        // all leaves are on the same level, so their ancestors are as well
+        let mut dirty = Vec::new_in(alloc.clone());
+
+        if count == previous_count {
+            for (ptr, &point) in replaced.zip(points) {
+                let idx = leaves_next_pow2 + ptr;
+                let node = Node::leaf(point, ptr);
+
+                let previous = self.nodes[idx as usize].replace(node);
+
+                if previous.into_expanded() == node.into_expanded() {
+                    continue;
+                }
+
+                if let Some(parent) = parent(idx) {
+                    dirty.push(parent.get());
+                }
+            }
+
+            return Some(dirty);
+        }
+
+        // every leaf from the first replaced one moves, so the nodes are copied
+        let total = (leaves_next_pow2 + count) as usize;
+        let kept = (leaves_next_pow2 + replaced.start) as usize;
+
+        let mut nodes = Box::new_uninit_slice_in(total, alloc);
+
+        for (node, cell) in nodes.iter_mut().zip(&self.nodes[..kept]) {
+            node.write(cell.get());
+        }
+
+        let behind = (replaced.end..previous_count).map(|ptr| self.leaf_point(ptr));
+
+        for (ptr, point) in (replaced.start..).zip(points.iter().copied().chain(behind)) {
+            nodes[(leaves_next_pow2 + ptr) as usize].write(Node::leaf(point, ptr));
+        }
+
+        // the leaves past the new end are gone, so their ancestors change as well
+        for ptr in replaced.start..count.max(previous_count) {
+            if let Some(parent) = parent(leaves_next_pow2 + ptr) {
+                dirty.push(parent.get());
+            }
+        }
+
+        // every node up to `kept` was copied and every leaf after it written
+        self.nodes = into_cells(unsafe { nodes.assume_init() });
+        Some(dirty)
+    }
+
+    /// The index of the leaf at `point`, which has to be in the tree.
+    fn find_leaf<C: Curve>(&self, point: I16Vec2, curve: C) -> u32 {
+        let key = curve.key(point);
+
+        let (mut low, mut high) = (0, self.leaf_count());
+
+        while low < high {
+            let mid = low + (high - low) / 2;
+
+            if curve.key(self.leaf_point(mid)) < key {
+                low = mid + 1;
+            } else {
+                high = mid;
+            }
+        }
+
+        debug_assert_eq!(self.leaf_point(low), point);
+        low
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::I16Vec2;
+
+    use crate::node::Node;
+    use crate::{Bvh, Data, Morton, Point};
+
+    #[derive(Debug, Clone)]
+    struct Entity {
+        point: I16Vec2,
+        data: Vec<u8>,
+    }
+
+    impl Point for Entity {
+        fn point(&self) -> I16Vec2 {
+            self.point
+        }
+    }
+
+    impl Data for Entity {
+        type Unit = u8;
+
+        fn data<'a: 'c, 'b: 'c, 'c>(&'a self, _context: Self::Context<'b>) -> &'c [u8] {
+            &self.data
+        }
+    }
+
+    fn assert_identical(rebuilt: &Bvh<Vec<u8>>, built: &Bvh<Vec<u8>>) {
+        // `Node` is a union, so compare the raw bits
+        let bits = |bvh: &Bvh<Vec<u8>>| -> Vec<u64> {
+            bvh.nodes
+                .iter()
+                .map(|node| unsafe { std::mem::transmute::<Node, u64>(node.get()) })
+                .collect()
+        };
+
+        assert_eq!(bits(rebuilt), bits(built));
+        assert_eq!(rebuilt.leaves, built.leaves);
+        assert_eq!(rebuilt.data, built.data);
+        assert_eq!(rebuilt.cost(), built.cost());
+    }
+
+    #[test]
+    fn test_rebuild_is_identical() {
+        let mut rng = fastrand::Rng::with_seed(5);
+
+        let entity = |rng: &mut fastrand::Rng| Entity {
+            point: I16Vec2::new(rng.i16(-30..30), rng.i16(-30..30)),
+            data: (0..rng.u8(0..4)).map(|_| rng.u8(..)).collect(),
+        };
+
+        let mut input: Vec<_> = (0..500).map(|_| entity(&mut rng)).collect();
+        let mut bvh = Bvh::build(&mut input, ());
+
+        for round in 0..100 {
+            let mut changed = Vec::new();
+
+            // nothing changed at all
+            if round % 10 == 0 {
+                let cost = bvh.rebuild(&mut input, &changed, ());
+                assert_eq!(cost, bvh.cost());
+                assert_identical(&bvh, &Bvh::build(&mut input.clone(), ()));
+                continue;
+            }
+
+            // move a few points
+            for _ in 0..rng.usize(0..10) {
+                let i = rng.usize(..input.len());
+                input[i].point += I16Vec2::new(rng.i16(-2..=2), rng.i16(-2..=2));
+                changed.push(i);
+            }
+
+            // change some data
+            for _ in 0..rng.usize(0..10) {
+                let i = rng.usize(..input.len());
+                input[i].data = (0..rng.u8(0..4)).map(|_| rng.u8(..)).collect();
+                changed.push(i);
+            }
+
+            // and sometimes add or remove elements, enough to cross powers of two
+            match rng.u8(..4) {
+                0 => {
+                    for _ in 0..rng.usize(..=input.len() / 2) {
+                        let i = rng.usize(..input.len());
+                        input.swap_remove(i);
+                        changed.push(i);
+                    }
+                }
+                1 => input.extend((0..rng.usize(..=input.len())).map(|_| entity(&mut rng))),
+                2 => input.truncate(input.len() - rng.usize(..=input.len() / 4)),
+                _ => {}
+            }
+
+            let cost = bvh.rebuild(&mut input, &changed, ());
+            let built = Bvh::build(&mut input.clone(), ());
+
+            assert_eq!(cost, built.cost());
+            assert_identical(&bvh, &built);
+        }
+
+        bvh.rebuild::<Entity>(&mut [], &[], ());
+        assert_identical(&bvh, &Bvh::build::<Entity>(&mut [], ()));
+
+        // the same curve has to be used for every rebuild
+        let mut bvh = Bvh::build_with_curve(&mut input, Morton, ());
+
+        for _ in 0..10 {
+            let changed: Vec<_> = (0..rng.usize(0..10))
+                .map(|_| rng.usize(..input.len()))
+                .collect();
+
+            for &i in &changed {
+                input[i].point += I16Vec2::new(rng.i16(-2..=2), rng.i16(-2..=2));
+            }
+
+            bvh.rebuild_with_curve(&mut input, &changed, Morton, ());
+            let built = Bvh::build_with_curve(&mut input.clone(), Morton, ());
+
+            assert_identical(&bvh, &built);
+        }
+    }
+}
@@ -0,0 +1,230 @@
+//! Moving leaves without rebuilding the tree.
+use std::alloc::Allocator;
+use std::cell::Cell;
+
+use glam::I16Vec2;
+use more_asserts::assert_lt;
+
+use crate::node::Node;
+use crate::{child_left, child_right, merge_children, node_cost, parent, Bvh};
+
+impl<L, A: Allocator> Bvh<L, A> {
+    /// The sum of the half perimeters of all inner nodes.
+    ///
+    /// This is a rough estimate of how many nodes a query has to visit, so it can be compared
+    /// with the cost right after a build to decide when a [`Self::refit`] tree is worth
+    /// rebuilding.
+    ///
+    /// The first call sums up all nodes, after that it is kept up to date by [`Self::refit`] and
+    /// [`Bvh::rebuild`].
+    pub fn cost(&self) -> u64 {
+        if let Some(cost) = self.cost.get() {
+            return cost;
+        }
+
+        let cost = self.nodes.iter().map(|node| node_cost(node.get())).sum();
+        self.cost.set(Some(cost));
+        cost
+    }
+
+    /// Moves existing leaves to new points and recomputes the `Aabb`s of their ancestors.
+    ///
+    /// `updates` holds `(leaf, point)` pairs where `leaf` is the index of the leaf in the order
+    /// of [`Self::iter_leaf_ranges`]. Only the nodes above moved leaves are visited, and a node
+    /// whose bounds did not change stops the propagation.
+    ///
+    /// The order of leaves and data is kept, so the tree gets worse the further points move from
+    /// where they were when it was built. Returns the new [`Self::cost`].
+    ///
+    /// # Panics
+    /// If a leaf is out of bounds or the tree was built with [`Bvh::build_sah_in`] (see
+    /// [`Self::is_implicit`]).
+    pub fn refit(&mut self, updates: &[(u32, I16Vec2)]) -> u64 {
+        assert!(self.is_implicit(), "refit needs the implicit layout");
+
+        let leaf_count = self.leaf_count();
+        let leaves_next_pow2 = self.leaves_next_pow2();
+
+        // all leaves are on the same level, so their ancestors are as well
+        let mut dirty = Vec::with_capacity(updates.len());
+
+        for &(ptr, point) in updates {
+            assert_lt!(ptr, leaf_count, "leaf {ptr} is out of bounds");
+
+            let idx = leaves_next_pow2 + ptr;
+            self.nodes[idx as usize].set(Node::leaf(point, ptr));
+
+            if let Some(parent) = parent(idx) {
+                dirty.push(parent.get());
+            }
+        }
+
+        self.refit_dirty(dirty);
+
+        self.cost()
+    }
+
+    /// Recomputes the inner nodes in `dirty` and their ancestors bottom-up, stopping at nodes
+    /// that did not change.
+    ///
+    /// All nodes in `dirty` have to be on the same level.
+    pub(crate) fn refit_dirty<B: Allocator + Clone>(&self, mut dirty: Vec<u32, B>) {
+        let leaf_count = self.leaf_count();
+
+        // leaves do not add to the cost, so the inner nodes still sum up to the old one
+        let mut cost = self.cost();
+
+        let mut next = Vec::with_capacity_in(dirty.len(), dirty.allocator().clone());
+
+        while !dirty.is_empty() {
+            dirty.sort_unstable();
+            dirty.dedup();
+
+            for &idx in &dirty {
+                let child = |idx: u32| {
+                    self.nodes
+                        .get(idx as usize)
+                        .map(Cell::get)
+                        .and_then(Node::into_expanded)
+                };
+
+                let node = merge_children(
+                    child(child_left(idx)),
+                    child(child_right(idx)),
+                    leaf_count as usize,
+                );
+
+                let previous = self.nodes[idx as usize].replace(node);
+
+                if previous.into_expanded() == node.into_expanded() {
+                    continue;
+                }
+
+                cost = cost - node_cost(previous) + node_cost(node);
+
+                if let Some(parent) = parent(idx) {
+                    next.push(parent.get());
+                }
+            }
+
+            std::mem::swap(&mut dirty, &mut next);
+            next.clear();
+        }
+
+        self.cost.set(Some(cost));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::I16Vec2;
+
+    use crate::node::Expanded;
+    use crate::{child_left, child_right, node_cost, Aabb, Bvh, Data, Point};
+
+    #[derive(Debug, Copy, Clone)]
+    struct Entity {
+        point: I16Vec2,
+        id: u32,
+    }
+
+    impl Point for Entity {
+        fn point(&self) -> I16Vec2 {
+            self.point
+        }
+    }
+
+    impl Data for Entity {
+        type Unit = u32;
+
+        fn data<'a: 'c, 'b: 'c, 'c>(&'a self, _context: Self::Context<'b>) -> &'c [u32] {
+            std::slice::from_ref(&self.id)
+        }
+    }
+
+    /// Checks that every inner node is exactly the union of its children and that the cost is
+    /// up to date.
+    #[allow(clippy::cast_possible_truncation)]
+    fn check_tight(bvh: &Bvh<Vec<u32>>) {
+        let child = |idx: u32| {
+            bvh.nodes
+                .get(idx as usize)
+                .and_then(|node| node.get().into_expanded())
+        };
+
+        let mut cost = 0;
+
+        for idx in 1..bvh.nodes.len() as u32 {
+            let node = bvh.nodes[idx as usize].get();
+            cost += node_cost(node);
+
+            let Some(Expanded::Aabb(aabb)) = node.into_expanded() else {
+                continue;
+            };
+
+            let bounds = [child(child_left(idx)), child(child_right(idx))]
+                .into_iter()
+                .flatten()
+                .map(|child| match child {
+                    Expanded::Aabb(aabb) => aabb,
+                    Expanded::Leaf(leaf) => Aabb::point(leaf.point),
+                })
+                .reduce(Aabb::merge)
+                .unwrap();
+
+            assert_eq!(aabb, bounds, "node {idx}");
+        }
+
+        assert_eq!(bvh.cost(), cost);
+    }
+
+    #[test]
+    fn test_refit() {
+        let mut rng = fastrand::Rng::with_seed(3);
+
+        let mut input: Vec<_> = (0..100)
+            .map(|id| Entity {
+                point: I16Vec2::new(rng.i16(-50..50), rng.i16(-50..50)),
+                id,
+            })
+            .collect();
+
+        let mut bvh = Bvh::build(&mut input, ());
+        check_tight(&bvh);
+
+        let built = bvh.cost();
+        let original: Vec<_> = bvh
+            .iter_leaf_ranges()
+            .zip(0..)
+            .map(|((point, _), ptr)| (ptr, point))
+            .collect();
+
+        for _ in 0..20 {
+            let updates: Vec<_> = (0..rng.usize(0..10))
+                .map(|_| {
+                    let ptr = rng.u32(0..bvh.leaf_count());
+                    let offset = I16Vec2::new(rng.i16(-3..=3), rng.i16(-3..=3));
+                    (ptr, bvh.leaf_point(ptr) + offset)
+                })
+                .collect();
+
+            let cost = bvh.refit(&updates);
+            assert_eq!(cost, bvh.cost());
+            check_tight(&bvh);
+
+            for &(ptr, _) in &updates {
+                // the last update of a leaf wins
+                let last = updates
+                    .iter()
+                    .rev()
+                    .find(|&&(other, _)| other == ptr)
+                    .unwrap();
+                assert_eq!(bvh.leaf_point(ptr), last.1);
+            }
+        }
+
+        // moving everything back restores the original tree
+        assert_eq!(bvh.refit(&original), built);
+        check_tight(&bvh);
+    }
+}
@@ -0,0 +1,393 @@
+//! Building a tree with explicit children using the surface area heuristic.
+//!
+//! The implicit layout splits the leaves in halves along the curve, which keeps the tree
+//! balanced but can make nodes large when points are clustered. Here every split is chosen to
+//! minimise the sum of `half_perimeter * leaves` of both children (the 2D version of the surface
+//! area heuristic), evaluated at a fixed number of bins along the longer axis.
+//!
+//! Inner nodes take the indices `1..n` and leaf `ptr` is at `n + ptr`, so leaves stay contiguous
+//! and every query works on both layouts.
+use std::alloc::{Allocator, Global};
+
+use glam::I16Vec2;
+
+use crate::node::{Leaf, Node};
+use crate::sealed::PointWithData;
+use crate::{process_input, sort, Aabb, Bvh, Hilbert, Layout};
+
+/// The number of candidate splits per node is one less than this.
+const BINS: usize = 16;
+
+/// The maximum depth of a tree, so queries never overflow their fixed size stacks.
+const MAX_DEPTH: u32 = 30;
+
+impl<T> Bvh<Vec<T>> {
+    /// See [`Bvh::build_sah_in`].
+    #[must_use]
+    pub fn build_sah<I>(input: &mut [I], context: I::Context<'_>) -> Self
+    where
+        I: PointWithData<Unit = T>,
+        T: Copy + 'static,
+    {
+        Self::build_sah_in(input, Global, context)
+    }
+}
+
+impl<T, A: Allocator + Clone> Bvh<Vec<T, A>, A> {
+    /// Builds a tree with explicit children, splitting with a binned surface area heuristic
+    /// instead of in halves along the Hilbert curve.
+    ///
+    /// This takes longer to build but gives tighter nodes for clustered input, e.g. two groups
+    /// of points far apart. The result has the same query API; compare [`Self::cost`] (or the
+    /// nodes visited with the `stats` feature) to pick a layout for a workload.
+    ///
+    /// `input` is left sorted along the Hilbert curve, which is not the order of the leaves.
+    /// The tree can not be refit, see [`Self::is_implicit`].
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn build_sah_in<I>(input: &mut [I], alloc: A, context: I::Context<'_>) -> Self
+    where
+        I: PointWithData<Unit = T>,
+        T: Copy + 'static,
+    {
+        if input.is_empty() {
+            return Self::empty_in(alloc);
+        }
+
+        // sorting groups equal points into one leaf
+        sort::sort_by_curve(input, Hilbert, alloc.clone());
+
+        let (data, leaves, points) = process_input(input, alloc.clone(), context);
+
+        let len = points.len();
+
+        let mut order = Vec::with_capacity_in(len, alloc.clone());
+        order.extend(0..len as u32);
+
+        let mut nodes = unsafe { Box::new_zeroed_slice_in(len * 2, alloc.clone()).assume_init() };
+        let mut children = unsafe { Box::new_zeroed_slice_in(len, alloc.clone()).assume_init() };
+
+        // (index of the node, range of `order` below it, depth)
+        let mut stack = Vec::new_in(alloc.clone());
+        stack.push((1, 0..len, 0));
+        let mut next_inner = 2;
+
+        while let Some((idx, range, depth)) = stack.pop() {
+            if range.len() == 1 {
+                let ptr = range.start as u32;
+                let point = points[order[range.start] as usize];
+                nodes[idx as usize] = Node::leaf(point, ptr);
+                continue;
+            }
+
+            let bounds = Aabb::enclosing_aabb(
+                order[range.clone()]
+                    .iter()
+                    .map(|&leaf| points[leaf as usize]),
+            );
+
+            nodes[idx as usize] = Node::aabb(bounds);
+
+            let order = &mut order[range.clone()];
+
+            let mid = if needs_balanced_split(order.len(), depth) {
+                median_split(order, &points, bounds)
+            } else {
+                binned_split(order, &points, bounds)
+            };
+
+            let mid = range.start + mid;
+            let halves = [range.start..mid, mid..range.end];
+
+            let [left, right] = halves.clone().map(|half| {
+                if half.len() == 1 {
+                    (len + half.start) as u32
+                } else {
+                    next_inner += 1;
+                    next_inner - 1
+                }
+            });
+
+            children[idx as usize] = [left, right];
+
+            let [left_half, right_half] = halves;
+            stack.push((right, right_half, depth + 1));
+            stack.push((left, left_half, depth + 1));
+        }
+
+        let (data, leaves) = reorder_leaves(&order, &data, &leaves, alloc);
+
+        let mut bvh = Self::from_parts(nodes, data, leaves, input.len());
+        bvh.layout = Layout::Explicit(children);
+        bvh
+    }
+}
+
+/// Whether a node with `len` leaves at `depth` has to be split in halves for the tree to stay
+/// within [`MAX_DEPTH`].
+const fn needs_balanced_split(len: usize, depth: u32) -> bool {
+    len.next_power_of_two().ilog2() + depth >= MAX_DEPTH
+}
+
+/// Partitions `order` into two halves of equal size along the longer axis of `bounds`.
+fn median_split(order: &mut [u32], points: &[I16Vec2], bounds: Aabb) -> usize {
+    let axis = longest_axis(bounds);
+    let mid = order.len() / 2;
+    order.select_nth_unstable_by_key(mid, |&leaf| points[leaf as usize][axis]);
+    mid
+}
+
+/// Partitions `order` at the cheapest of the bin boundaries along the longer axis of `bounds`.
+#[allow(
+    clippy::cast_possible_truncation,
+    clippy::cast_possible_wrap,
+    clippy::cast_sign_loss
+)]
+fn binned_split(order: &mut [u32], points: &[I16Vec2], bounds: Aabb) -> usize {
+    let axis = longest_axis(bounds);
+
+    let min = i64::from(bounds.min[axis]);
+    let extent = i64::from(bounds.max[axis]) - min + 1;
+
+    // leaves have distinct points, so `extent >= 2` on the longer axis. The minimum lands in bin
+    // 0 and the maximum in bin `(extent - 1) * BINS / extent >= BINS / 2`, so some boundary has
+    // points on both sides
+    let bin = |leaf: u32| {
+        ((i64::from(points[leaf as usize][axis]) - min) * BINS as i64 / extent) as usize
+    };
+
+    let mut counts = [0u64; BINS];
+    let mut bin_bounds = [None::<Aabb>; BINS];
+
+    for &leaf in order.iter() {
+        let bin = bin(leaf);
+        let point = points[leaf as usize];
+        counts[bin] += 1;
+        bin_bounds[bin] =
+            Some(bin_bounds[bin].map_or_else(|| Aabb::point(point), |aabb| aabb.enclose(point)));
+    }
+
+    // the cost of everything right of each boundary, from the right
+    let mut right_costs = [None; BINS];
+    let mut right = None::<Aabb>;
+    let mut right_count = 0;
+
+    for i in (1..BINS).rev() {
+        right = merge(right, bin_bounds[i]);
+        right_count += counts[i];
+        right_costs[i] = right.map(|right| u64::from(right.half_perimeter()) * right_count);
+    }
+
+    let mut best = None::<(u64, usize)>;
+    let mut left = None::<Aabb>;
+    let mut left_count = 0;
+
+    for i in 1..BINS {
+        left = merge(left, bin_bounds[i - 1]);
+        left_count += counts[i - 1];
+
+        let (Some(left), Some(right_cost)) = (left, right_costs[i]) else {
+            continue;
+        };
+
+        let cost = u64::from(left.half_perimeter()) * left_count + right_cost;
+
+        if best.is_none_or(|(best, _)| cost < best) {
+            best = Some((cost, i));
+        }
+    }
+
+    let (_, split) = best.expect("the minimum and maximum are in different bins");
+
+    let mut mid = 0;
+
+    for i in 0..order.len() {
+        if bin(order[i]) < split {
+            order.swap(i, mid);
+            mid += 1;
+        }
+    }
+
+    mid
+}
+
+fn merge(a: Option<Aabb>, b: Option<Aabb>) -> Option<Aabb> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(a.merge(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// `0` for x and `1` for y.
+fn longest_axis(bounds: Aabb) -> usize {
+    let [x, y] = bounds.lens();
+    usize::from(y > x)
+}
+
+/// The data and leaves in the order of `order`, where `order[i]` is the leaf that moves to `i`.
+#[allow(clippy::cast_possible_truncation)]
+fn reorder_leaves<T: Copy, A: Allocator + Clone>(
+    order: &[u32],
+    data: &[T],
+    leaves: &[Leaf],
+    alloc: A,
+) -> (Vec<T, A>, Vec<Leaf, A>) {
+    let mut result_data = Vec::with_capacity_in(data.len(), alloc.clone());
+    let mut result_leaves = Vec::with_capacity_in(leaves.len() + 1, alloc);
+
+    for &leaf in order {
+        let start = leaves[leaf as usize].element_index as usize;
+        let end = leaves
+            .get(leaf as usize + 1)
+            .map_or(data.len(), |next| next.element_index as usize);
+
+        result_leaves.push(Leaf::new(result_data.len() as u32));
+        result_data.extend_from_slice(&data[start..end]);
+    }
+
+    (result_data, result_leaves)
+}
+
+#[cfg(test)]
+mod tests {
+    use glam::I16Vec2;
+
+    use crate::node::Expanded;
+    use crate::{Aabb, Bvh, Data, Point, ROOT_IDX};
+
+    #[derive(Debug, Copy, Clone)]
+    struct Entity {
+        point: I16Vec2,
+        id: u32,
+    }
+
+    impl Point for Entity {
+        fn point(&self) -> I16Vec2 {
+            self.point
+        }
+    }
+
+    impl Data for Entity {
+        type Unit = u32;
+
+        fn data<'a: 'c, 'b: 'c, 'c>(&'a self, _context: Self::Context<'b>) -> &'c [u32] {
+            std::slice::from_ref(&self.id)
+        }
+    }
+
+    /// Checks that every inner node is exactly the union of its children, that the leaves below
+    /// a node are `subtree_leaves` and returns the depth.
+    fn check_tree(bvh: &Bvh<Vec<u32>>, idx: u32) -> u32 {
+        let node = unsafe { bvh.get_node(idx) };
+
+        match node.into_expanded().unwrap() {
+            Expanded::Leaf(leaf) => {
+                assert_eq!(bvh.subtree_leaves(idx), leaf.ptr..leaf.ptr + 1);
+                assert_eq!(bvh.leaf_point(leaf.ptr), leaf.point);
+                0
+            }
+            Expanded::Aabb(aabb) => {
+                let left = bvh.left_child(idx);
+                let right = bvh.right_child(idx);
+
+                let leaves = bvh.subtree_leaves(idx);
+                assert_eq!(leaves.start, bvh.subtree_leaves(left).start);
+                assert_eq!(leaves.end, bvh.subtree_leaves(right).end);
+                assert_eq!(
+                    bvh.subtree_leaves(left).end,
+                    bvh.subtree_leaves(right).start
+                );
+
+                let bounds = Aabb::enclosing_aabb(leaves.map(|ptr| bvh.leaf_point(ptr)));
+                assert_eq!(aabb, bounds, "node {idx}");
+
+                1 + check_tree(bvh, left).max(check_tree(bvh, right))
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_sah() {
+        let mut rng = fastrand::Rng::with_seed(9);
+
+        for len in [0, 1, 2, 3, 10, 100, 1000] {
+            let mut input: Vec<_> = (0..len)
+                .map(|id| Entity {
+                    point: I16Vec2::new(rng.i16(-50..50), rng.i16(-50..50)),
+                    id,
+                })
+                .collect();
+
+            let bvh = Bvh::build_sah(&mut input, ());
+
+            let mut ids = bvh.elements().to_vec();
+            ids.sort_unstable();
+            assert_eq!(ids, (0..len).collect::<Vec<_>>());
+
+            if len > 0 {
+                check_tree(&bvh, ROOT_IDX);
+            }
+
+            // every element is in the leaf of its point
+            for (point, range) in bvh.iter_leaf_ranges() {
+                for &id in &bvh.elements()[range.start as usize..range.end as usize] {
+                    assert_eq!(input.iter().find(|x| x.id == id).unwrap().point, point);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_build_sah_clusters() {
+        let mut rng = fastrand::Rng::with_seed(4);
+
+        // two tight clusters in opposite corners and a few stragglers in between
+        let mut input: Vec<_> = (0..1000)
+            .map(|id| {
+                let centre = match id % 10 {
+                    0 => I16Vec2::ZERO,
+                    1..5 => I16Vec2::splat(-20_000),
+                    _ => I16Vec2::splat(20_000),
+                };
+
+                Entity {
+                    point: centre + I16Vec2::new(rng.i16(-200..200), rng.i16(-200..200)),
+                    id,
+                }
+            })
+            .collect();
+
+        let implicit = Bvh::build(&mut input, ());
+        let sah = Bvh::build_sah(&mut input, ());
+
+        check_tree(&sah, ROOT_IDX);
+        assert!(
+            sah.cost() < implicit.cost(),
+            "{} >= {}",
+            sah.cost(),
+            implicit.cost()
+        );
+    }
+
+    #[test]
+    fn test_needs_balanced_split() {
+        use super::{needs_balanced_split, MAX_DEPTH};
+
+        assert!(!needs_balanced_split(1000, 0));
+        assert!(!needs_balanced_split(2, MAX_DEPTH - 2));
+        assert!(needs_balanced_split(2, MAX_DEPTH - 1));
+        assert!(needs_balanced_split(1 << 20, MAX_DEPTH - 20));
+
+        // once a node is split in halves so are all nodes below it, which ends at `MAX_DEPTH`
+        let (mut len, mut depth) = (3, MAX_DEPTH - 2);
+        assert!(needs_balanced_split(len, depth));
+
+        while len > 1 {
+            assert!(needs_balanced_split(len, depth));
+            len = len.div_ceil(2);
+            depth += 1;
+        }
+
+        assert!(depth <= MAX_DEPTH);
+    }
+}
@@ -0,0 +1,445 @@
+//! Query shapes for range queries such as [`Bvh::get_in`](crate::Bvh::get_in).
+//!
+//! A shape only has to answer two questions exactly: whether a leaf's point is inside of it and
+//! whether a node's [`Aabb`] can contain such a point. The latter may return false positives, that
+//! only costs visiting more nodes.
+use glam::{DVec2, I16Vec2, Vec2};
+
+use crate::aabb::Aabb;
+use crate::{Euclidean, Metric};
+
+pub trait QueryShape {
+    /// Whether any point inside `aabb` might be inside the shape.
+    ///
+    /// Must never return `false` if a point in `aabb` is inside, false positives are fine.
+    fn intersects_aabb(&self, aabb: Aabb) -> bool;
+
+    fn contains_point(&self, point: I16Vec2) -> bool;
+
+    /// Whether every point inside `aabb` is inside the shape.
+    ///
+    /// Must never return `true` if a point in `aabb` is outside, false negatives are fine. Used to
+    /// take whole subtrees without checking every leaf.
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        let _ = aabb;
+        false
+    }
+}
+
+impl<Q: QueryShape + ?Sized> QueryShape for &Q {
+    fn intersects_aabb(&self, aabb: Aabb) -> bool {
+        (**self).intersects_aabb(aabb)
+    }
+
+    fn contains_point(&self, point: I16Vec2) -> bool {
+        (**self).contains_point(point)
+    }
+
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        (**self).contains_aabb(aabb)
+    }
+}
+
+impl QueryShape for Aabb {
+    fn intersects_aabb(&self, aabb: Aabb) -> bool {
+        self.intersects(aabb)
+    }
+
+    fn contains_point(&self, point: I16Vec2) -> bool {
+        Self::contains_point(*self, point)
+    }
+
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        self.contains_point(aabb.min) && self.contains_point(aabb.max)
+    }
+}
+
+/// All points within `radius` of `center`, measured with a [`Metric`].
+#[derive(Debug, Copy, Clone)]
+pub struct Circle<M = Euclidean> {
+    center: I16Vec2,
+    radius: u32,
+    metric: M,
+}
+
+impl Circle {
+    #[must_use]
+    pub fn new(center: I16Vec2, radius: u16) -> Self {
+        Self::with_metric(center, radius, Euclidean)
+    }
+}
+
+impl<M: Metric> Circle<M> {
+    #[must_use]
+    pub fn with_metric(center: I16Vec2, radius: u16, metric: M) -> Self {
+        Self {
+            center,
+            radius: metric.radius(radius),
+            metric,
+        }
+    }
+}
+
+impl<M: Metric> QueryShape for Circle<M> {
+    fn intersects_aabb(&self, aabb: Aabb) -> bool {
+        self.metric.min_max_distance(aabb, self.center).0 <= self.radius
+    }
+
+    fn contains_point(&self, point: I16Vec2) -> bool {
+        self.metric.distance(point, self.center) <= self.radius
+    }
+
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        self.metric.min_max_distance(aabb, self.center).1 <= self.radius
+    }
+}
+
+/// Points inside `include` that are not inside `exclude`, e.g. the chunks that became visible
+/// after the view distance grew.
+///
+/// Subtrees fully inside `exclude` are skipped without visiting their leaves.
+#[derive(Debug, Copy, Clone)]
+pub struct Difference<I, E> {
+    pub include: I,
+    pub exclude: E,
+}
+
+impl<I, E> Difference<I, E> {
+    pub const fn new(include: I, exclude: E) -> Self {
+        Self { include, exclude }
+    }
+}
+
+impl<I: QueryShape, E: QueryShape> QueryShape for Difference<I, E> {
+    fn intersects_aabb(&self, aabb: Aabb) -> bool {
+        self.include.intersects_aabb(aabb) && !self.exclude.contains_aabb(aabb)
+    }
+
+    fn contains_point(&self, point: I16Vec2) -> bool {
+        self.include.contains_point(point) && !self.exclude.contains_point(point)
+    }
+
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        self.include.contains_aabb(aabb) && !self.exclude.intersects_aabb(aabb)
+    }
+}
+
+fn corners(aabb: Aabb) -> [DVec2; 4] {
+    let min = aabb.min.as_dvec2();
+    let max = aabb.max.as_dvec2();
+    [min, DVec2::new(max.x, min.y), max, DVec2::new(min.x, max.y)]
+}
+
+/// A convex polygon, boundary included.
+#[derive(Debug, Clone)]
+pub struct ConvexPolygon {
+    /// counter-clockwise
+    vertices: Vec<DVec2>,
+    bounds: Aabb,
+}
+
+impl ConvexPolygon {
+    /// Creates a polygon from its vertices in either clockwise or counter-clockwise order.
+    ///
+    /// # Panics
+    /// If there are fewer than three vertices.
+    #[must_use]
+    #[allow(clippy::cast_possible_truncation)]
+    pub fn new(vertices: impl IntoIterator<Item = Vec2>) -> Self {
+        let mut vertices: Vec<DVec2> = vertices.into_iter().map(|v| v.as_dvec2()).collect();
+
+        assert!(
+            vertices.len() >= 3,
+            "a polygon needs at least three vertices"
+        );
+
+        let area2: f64 = (0..vertices.len())
+            .map(|i| vertices[i].perp_dot(vertices[(i + 1) % vertices.len()]))
+            .sum();
+
+        if area2 < 0.0 {
+            vertices.reverse();
+        }
+
+        let (min, max) = vertices
+            .iter()
+            .fold((DVec2::MAX, DVec2::MIN), |(min, max), &v| {
+                (min.min(v), max.max(v))
+            });
+
+        let clamp = |v: DVec2| v.clamp(DVec2::splat(-32768.0), DVec2::splat(32767.0));
+
+        // only lattice points matter, round inwards
+        let bounds = Aabb::new(
+            clamp(min.ceil()).as_i16vec2(),
+            clamp(max.floor()).as_i16vec2(),
+        );
+
+        Self { vertices, bounds }
+    }
+
+    fn edges(&self) -> impl Iterator<Item = (DVec2, DVec2)> + '_ {
+        let len = self.vertices.len();
+        (0..len).map(move |i| (self.vertices[i], self.vertices[(i + 1) % len]))
+    }
+
+    fn contains(&self, point: DVec2) -> bool {
+        self.edges()
+            .all(|(a, b)| (b - a).perp_dot(point - a) >= 0.0)
+    }
+}
+
+impl QueryShape for ConvexPolygon {
+    fn intersects_aabb(&self, aabb: Aabb) -> bool {
+        if !self.bounds.intersects(aabb) {
+            return false;
+        }
+
+        // separating axis: either one of the box axes (the bounds check above) or an edge normal
+        let corners = corners(aabb);
+
+        self.edges().all(|(a, b)| {
+            let edge = b - a;
+            corners
+                .iter()
+                .any(|&corner| edge.perp_dot(corner - a) >= 0.0)
+        })
+    }
+
+    fn contains_point(&self, point: I16Vec2) -> bool {
+        self.contains(point.as_dvec2())
+    }
+
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        corners(aabb)
+            .into_iter()
+            .all(|corner| self.contains(corner))
+    }
+}
+
+const TOLERANCE: f64 = 1e-6;
+
+/// A player's view: a circular sector in front of `origin`.
+#[derive(Debug, Copy, Clone)]
+pub struct ViewWedge {
+    origin: DVec2,
+    facing: DVec2,
+    range2: f64,
+    cos_half_fov: f64,
+    /// the directions of the two straight edges, counter-clockwise and clockwise of `facing`
+    edges: [DVec2; 2],
+    /// bounding box of the sector
+    min: DVec2,
+    max: DVec2,
+    /// `fov <= 180°`, i.e. the wedge is convex
+    convex: bool,
+}
+
+impl ViewWedge {
+    /// `yaw` is the angle of the view direction in radians, counter-clockwise from the x axis.
+    /// `fov` is the full opening angle in radians.
+    #[must_use]
+    pub fn new(origin: Vec2, yaw: f32, fov: f32, range: f32) -> Self {
+        let origin = origin.as_dvec2();
+        let yaw = f64::from(yaw);
+        let half_fov = f64::from(fov) / 2.0;
+        let range = f64::from(range);
+
+        let facing = DVec2::from_angle(yaw);
+        let edges = [
+            DVec2::from_angle(yaw + half_fov),
+            DVec2::from_angle(yaw - half_fov),
+        ];
+
+        let mut wedge = Self {
+            origin,
+            facing,
+            range2: range * range,
+            cos_half_fov: half_fov.cos(),
+            edges,
+            min: origin,
+            max: origin,
+            convex: half_fov <= std::f64::consts::FRAC_PI_2,
+        };
+
+        // the sector spans its straight edges and every axis direction that lies inside of it
+        let extremes = [DVec2::X, DVec2::NEG_X, DVec2::Y, DVec2::NEG_Y]
+            .into_iter()
+            .filter(|&axis| axis.dot(facing) >= wedge.cos_half_fov)
+            .chain(edges);
+
+        for direction in extremes {
+            let point = origin + direction * range;
+            wedge.min = wedge.min.min(point);
+            wedge.max = wedge.max.max(point);
+        }
+
+        // `intersects_aabb` may not reject points that `contains` accepts due to rounding
+        wedge.min -= TOLERANCE;
+        wedge.max += TOLERANCE;
+
+        wedge
+    }
+
+    fn contains(&self, point: DVec2) -> bool {
+        let delta = point - self.origin;
+        let length2 = delta.length_squared();
+
+        length2 <= self.range2 && delta.dot(self.facing) >= length2.sqrt() * self.cos_half_fov
+    }
+}
+
+impl QueryShape for ViewWedge {
+    fn intersects_aabb(&self, aabb: Aabb) -> bool {
+        let min = aabb.min.as_dvec2();
+        let max = aabb.max.as_dvec2();
+
+        if min.cmpgt(self.max).any() || max.cmplt(self.min).any() {
+            return false;
+        }
+
+        let closest = self.origin.clamp(min, max);
+        if closest.distance_squared(self.origin) > self.range2 {
+            return false;
+        }
+
+        if !self.convex {
+            return true;
+        }
+
+        // the box must not be fully behind either straight edge of the wedge
+        let [left, right] = self.edges;
+        let corners = corners(aabb);
+
+        let inside_left = |corner: &DVec2| left.perp_dot(*corner - self.origin) <= TOLERANCE;
+        let inside_right = |corner: &DVec2| right.perp_dot(*corner - self.origin) >= -TOLERANCE;
+
+        corners.iter().any(inside_left) && corners.iter().any(inside_right)
+    }
+
+    fn contains_point(&self, point: I16Vec2) -> bool {
+        self.contains(point.as_dvec2())
+    }
+
+    fn contains_aabb(&self, aabb: Aabb) -> bool {
+        self.convex
+            && corners(aabb)
+                .into_iter()
+                .all(|corner| self.contains(corner))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn all_points(aabb: Aabb) -> impl Iterator<Item = I16Vec2> {
+        (aabb.min.x..=aabb.max.x)
+            .flat_map(move |x| (aabb.min.y..=aabb.max.y).map(move |y| I16Vec2::new(x, y)))
+    }
+
+    /// Brute force check of the contract between the three methods.
+    fn check_consistent(shape: &impl QueryShape) {
+        fastrand::seed(7);
+
+        for _ in 0..2000 {
+            let a = I16Vec2::new(fastrand::i16(-12..12), fastrand::i16(-12..12));
+            let b = I16Vec2::new(fastrand::i16(-12..12), fastrand::i16(-12..12));
+            let aabb = Aabb::new(a.min(b), a.max(b));
+
+            let inside = all_points(aabb)
+                .filter(|&p| shape.contains_point(p))
+                .count();
+            let total = all_points(aabb).count();
+
+            if inside > 0 {
+                assert!(shape.intersects_aabb(aabb), "{aabb:?} has points inside");
+            }
+
+            if shape.contains_aabb(aabb) {
+                assert_eq!(inside, total, "{aabb:?} is not fully inside");
+            }
+        }
+    }
+
+    #[test]
+    fn test_aabb_shape() {
+        let aabb = Aabb::new(I16Vec2::new(-3, -2), I16Vec2::new(4, 5));
+        check_consistent(&aabb);
+
+        assert!(QueryShape::contains_aabb(
+            &aabb,
+            Aabb::new(I16Vec2::new(-3, 0), I16Vec2::new(0, 5))
+        ));
+    }
+
+    #[test]
+    fn test_circle() {
+        let circle = Circle::new(I16Vec2::new(1, -1), 5);
+        check_consistent(&circle);
+
+        assert!(circle.contains_point(I16Vec2::new(4, 3)));
+        assert!(!circle.contains_point(I16Vec2::new(5, 3)));
+
+        check_consistent(&Circle::with_metric(
+            I16Vec2::new(1, -1),
+            5,
+            crate::Manhattan,
+        ));
+    }
+
+    #[test]
+    fn test_convex_polygon() {
+        // clockwise triangle
+        let polygon = ConvexPolygon::new([
+            Vec2::new(-8.0, -8.0),
+            Vec2::new(0.5, 9.0),
+            Vec2::new(7.0, -3.0),
+        ]);
+        check_consistent(&polygon);
+
+        assert!(polygon.contains_point(I16Vec2::new(0, 0)));
+        assert!(polygon.contains_point(I16Vec2::new(-8, -8)));
+        assert!(!polygon.contains_point(I16Vec2::new(-7, 5)));
+        assert!(!polygon.intersects_aabb(Aabb::new(I16Vec2::new(-9, 4), I16Vec2::new(-5, 9))));
+        assert!(polygon.contains_aabb(Aabb::new(I16Vec2::new(-1, -1), I16Vec2::new(1, 1))));
+    }
+
+    #[test]
+    fn test_difference() {
+        let annulus = Difference::new(
+            Circle::new(I16Vec2::new(0, 0), 10),
+            Circle::new(I16Vec2::new(0, 0), 6),
+        );
+        check_consistent(&annulus);
+
+        assert!(annulus.contains_point(I16Vec2::new(8, 0)));
+        assert!(!annulus.contains_point(I16Vec2::new(6, 0)));
+        assert!(!annulus.intersects_aabb(Aabb::new(I16Vec2::new(-2, -2), I16Vec2::new(3, 3))));
+
+        let moved = Difference::new(
+            Aabb::new(I16Vec2::new(-4, -4), I16Vec2::new(4, 4)),
+            Aabb::new(I16Vec2::new(-5, -4), I16Vec2::new(3, 4)),
+        );
+        check_consistent(&moved);
+    }
+
+    #[test]
+    fn test_view_wedge() {
+        use std::f32::consts::{FRAC_PI_2, PI};
+
+        // looking up (+y) with a 90° field of view
+        let wedge = ViewWedge::new(Vec2::new(0.5, 0.0), FRAC_PI_2, FRAC_PI_2, 8.0);
+        check_consistent(&wedge);
+
+        assert!(wedge.contains_point(I16Vec2::new(0, 5)));
+        assert!(wedge.contains_point(I16Vec2::new(3, 4)));
+        assert!(!wedge.contains_point(I16Vec2::new(0, -1)));
+        assert!(!wedge.contains_point(I16Vec2::new(0, 9)));
+        assert!(!wedge.intersects_aabb(Aabb::new(I16Vec2::new(-9, -9), I16Vec2::new(9, -1))));
+
+        // wider than 180° is not convex anymore
+        let wedge = ViewWedge::new(Vec2::new(0.5, 0.5), 0.3, PI * 1.5, 10.0);
+        check_consistent(&wedge);
+    }
+}
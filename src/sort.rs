@@ -0,0 +1,448 @@
+//! Ordering the input along a [`Curve`] before the tree is built.
+//!
+//! All sorts order by `(key, original index)`, so they produce the exact same permutation as a
+//! stable sort by key.
+use std::alloc::Allocator;
+use std::ops::Range;
+
+use crate::{Curve, Point};
+
+/// Below this many elements [`sort_by_curve`] uses a comparison sort, as the fixed cost of the
+/// histograms outweighs the fewer passes.
+///
+/// The radix sort starts to win between 512 and 1024 elements, see the `bench_radix_threshold`
+/// test.
+const RADIX_THRESHOLD: usize = 1024;
+
+/// Sorts `input` by [`Curve::key`] with a radix sort, allocating its scratch space in `alloc`.
+///
+/// Small inputs are sorted with [`sort_by_curve_comparison`] instead.
+pub fn sort_by_curve<I: Point, C: Curve, A: Allocator + Clone>(
+    input: &mut [I],
+    curve: C,
+    alloc: A,
+) {
+    if input.len() < RADIX_THRESHOLD {
+        sort_by_curve_comparison(input, curve);
+    } else {
+        sort_by_curve_radix(input, curve, alloc);
+    }
+}
+
+/// Sorts `input` by [`Curve::key`] with [`radix_sort`], whatever its length.
+#[allow(clippy::cast_possible_truncation)]
+fn sort_by_curve_radix<I: Point, C: Curve, A: Allocator + Clone>(
+    input: &mut [I],
+    curve: C,
+    alloc: A,
+) {
+    let mut indices = Vec::with_capacity_in(input.len(), alloc.clone());
+    indices.extend(
+        input
+            .iter()
+            .enumerate()
+            .map(|(i, x)| (curve.key(x.point()), i as u32)),
+    );
+
+    radix_sort(&mut indices, alloc);
+
+    apply_permutation(input, &mut indices);
+}
+
+/// Sorts `input` by [`Curve::key`] with `sort_by_cached_key`.
+pub fn sort_by_curve_comparison<I: Point, C: Curve>(input: &mut [I], curve: C) {
+    input.sort_by_cached_key(|x| curve.key(x.point()));
+}
+
+/// A stable LSD radix sort of `(key, index)` pairs by key, one byte per pass.
+///
+/// Passes where every key has the same byte are skipped, which is common as points are usually
+/// close together.
+fn radix_sort<A: Allocator>(indices: &mut Vec<(u32, u32), A>, alloc: A) {
+    const PASSES: usize = 4;
+
+    let len = indices.len();
+
+    // the histograms of all passes are counted up front
+    let mut counts = [[0usize; 256]; PASSES];
+
+    for &(key, _) in indices.iter() {
+        for (pass, count) in counts.iter_mut().enumerate() {
+            count[(key >> (pass * 8)) as usize & 0xff] += 1;
+        }
+    }
+
+    let mut scratch = Vec::with_capacity_in(len, alloc);
+    scratch.resize(len, (0, 0));
+
+    let mut from = &mut indices[..];
+    let mut to = &mut scratch[..];
+    let mut swapped = false;
+
+    for (pass, count) in counts.iter().enumerate() {
+        if count.contains(&len) {
+            continue;
+        }
+
+        let mut offsets = [0usize; 256];
+        let mut sum = 0;
+
+        for (offset, &count) in offsets.iter_mut().zip(count) {
+            *offset = sum;
+            sum += count;
+        }
+
+        for &entry in from.iter() {
+            let byte = (entry.0 >> (pass * 8)) as usize & 0xff;
+            to[offsets[byte]] = entry;
+            offsets[byte] += 1;
+        }
+
+        std::mem::swap(&mut from, &mut to);
+        swapped = !swapped;
+    }
+
+    if swapped {
+        std::mem::swap(indices, &mut scratch);
+    }
+}
+
+/// How many swaps per element [`sort_changed_by_curve`] does before it gives up.
+const ADAPTIVE_SHIFTS_PER_ELEMENT: usize = 8;
+
+/// [`sort_by_curve`] for the sorted input of the previous build where only the elements at
+/// `changed` were changed or replaced. `changed` has to be sorted and deduplicated.
+///
+/// Every changed element is swapped past its neighbours until it is in place, so this takes
+/// `O(c + d)` for `c` changed elements that move `d` places in total, and keys are only computed
+/// for the elements that are compared. Returns the range of indices whose element may be
+/// different from before, or `None` once `d` gets too large, leaving `input` in an arbitrary
+/// order for [`sort_by_curve`].
+///
+/// Unlike the other sorts this is not stable: elements with equal keys, i.e. at the same point,
+/// may end up in a different order.
+pub fn sort_changed_by_curve<I: Point, C: Curve, A: Allocator>(
+    input: &mut [I],
+    changed: &[usize],
+    curve: C,
+    alloc: A,
+) -> Option<Range<usize>> {
+    let key = |input: &[I], i: usize| curve.key(input[i].point());
+
+    let mut budget = input.len().saturating_mul(ADAPTIVE_SHIFTS_PER_ELEMENT);
+    let mut touched = input.len()..0;
+
+    // where the changed elements are after they were moved left, ascending. Moving them left
+    // in ascending order leaves every element that is greater than one after it changed.
+    let mut moved = Vec::with_capacity_in(changed.len(), alloc);
+
+    for &start in changed {
+        let start_key = key(input, start);
+        let mut i = start;
+
+        while i > 0 && key(input, i - 1) > start_key {
+            budget = budget.checked_sub(1)?;
+            input.swap(i - 1, i);
+            i -= 1;
+        }
+
+        touched.start = touched.start.min(i);
+        touched.end = touched.end.max(start + 1);
+
+        // the changed elements that were passed moved one to the right
+        let passed = moved.partition_point(|&moved| moved < i);
+
+        for moved in &mut moved[passed..] {
+            *moved += 1;
+        }
+
+        moved.insert(passed, i);
+    }
+
+    // everything right of a changed element is sorted once the ones after it were moved right
+    for &start in moved.iter().rev() {
+        let start_key = key(input, start);
+        let mut i = start;
+
+        while i + 1 < input.len() && key(input, i + 1) < start_key {
+            budget = budget.checked_sub(1)?;
+            input.swap(i, i + 1);
+            i += 1;
+        }
+
+        touched.end = touched.end.max(i + 1);
+    }
+
+    if touched.is_empty() {
+        return Some(input.len()..input.len());
+    }
+
+    Some(touched)
+}
+
+/// [`sort_by_curve`] computing the keys and sorting them on the rayon thread pool.
+#[cfg(feature = "parallel")]
+#[allow(clippy::cast_possible_truncation)]
+pub fn par_sort_by_curve<I: Point + Send + Sync, C: Curve + Sync>(input: &mut [I], curve: C) {
+    use rayon::prelude::*;
+
+    let mut indices: Vec<(u32, u32)> = input
+        .par_iter()
+        .enumerate()
+        .map(|(i, x)| (curve.key(x.point()), i as u32))
+        .collect();
+
+    indices.par_sort_unstable();
+
+    apply_permutation(input, &mut indices);
+}
+
+/// Moves `input[indices[i].1]` to `input[i]` for every `i`.
+///
+/// This is the same in-place permutation `sort_by_cached_key` uses; `indices` is clobbered.
+fn apply_permutation<I>(input: &mut [I], indices: &mut [(u32, u32)]) {
+    for i in 0..input.len() {
+        let mut index = indices[i].1;
+
+        // everything before `i` was already swapped away, follow where it went
+        while (index as usize) < i {
+            index = indices[index as usize].1;
+        }
+
+        indices[i].1 = index;
+        input.swap(i, index as usize);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::Global;
+    use std::time::{Duration, Instant};
+
+    use glam::I16Vec2;
+
+    use super::*;
+    use crate::{Hilbert, Morton, RowMajor};
+
+    #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+    struct Tagged {
+        point: I16Vec2,
+        tag: u32,
+    }
+
+    impl Point for Tagged {
+        fn point(&self) -> I16Vec2 {
+            self.point
+        }
+    }
+
+    fn check_radix<C: Curve>(input: &[Tagged], curve: C) {
+        let mut comparison = input.to_vec();
+        sort_by_curve_comparison(&mut comparison, curve);
+
+        let mut radix = input.to_vec();
+        sort_by_curve(&mut radix, curve, Global);
+
+        assert_eq!(comparison, radix, "len {}", input.len());
+    }
+
+    #[test]
+    fn test_radix_sort_matches_comparison() {
+        let mut rng = fastrand::Rng::with_seed(13);
+
+        for (len, extent) in [
+            (0, 1),
+            (10, 20),
+            (RADIX_THRESHOLD - 1, 20),
+            (RADIX_THRESHOLD, 20),
+            (5_000, 20),
+            (5_000, i16::MAX),
+        ] {
+            let input: Vec<_> = (0..)
+                .take(len)
+                .map(|tag| Tagged {
+                    point: I16Vec2::new(rng.i16(-extent..extent), rng.i16(-extent..extent)),
+                    tag,
+                })
+                .collect();
+
+            check_radix(&input, Hilbert);
+            check_radix(&input, Morton);
+            check_radix(&input, RowMajor);
+        }
+    }
+
+    #[test]
+    fn test_radix_sort_byte_boundaries() {
+        let mut rng = fastrand::Rng::with_seed(17);
+
+        // keys on both sides of every byte, so each pass sees its byte change
+        let boundaries = [
+            0,
+            0xff,
+            0x100,
+            0xffff,
+            0x1_0000,
+            0xff_ffff,
+            0x100_0000,
+            u32::MAX,
+        ];
+
+        for len in [0, 1, 2, 255, 256, 257] {
+            let mut indices: Vec<(u32, u32)> = (0..len)
+                .map(|i| (boundaries[rng.usize(..boundaries.len())], i))
+                .collect();
+
+            let mut expected = indices.clone();
+            expected.sort_by_key(|&(key, _)| key);
+
+            radix_sort(&mut indices, Global);
+            assert_eq!(indices, expected, "len {len}");
+        }
+
+        // all keys equal skips every pass
+        let mut indices: Vec<(u32, u32)> = (0..300).map(|i| (0x1234_5678, i)).collect();
+        let expected = indices.clone();
+
+        radix_sort(&mut indices, Global);
+        assert_eq!(indices, expected);
+    }
+
+    #[test]
+    fn test_changed_sort_matches_serial() {
+        let mut rng = fastrand::Rng::with_seed(11);
+
+        let mut input: Vec<_> = (0..2_000)
+            .map(|tag| Tagged {
+                point: I16Vec2::new(rng.i16(-20..20), rng.i16(-20..20)),
+                tag,
+            })
+            .collect();
+
+        sort_by_curve_comparison(&mut input, Hilbert);
+
+        // nothing changed, nothing touched
+        assert_eq!(
+            sort_changed_by_curve(&mut input, &[], Hilbert, Global),
+            Some(input.len()..input.len())
+        );
+
+        for moves in [1, 5, 50] {
+            let mut changed: Vec<_> = (0..moves).map(|_| rng.usize(..input.len())).collect();
+            changed.sort_unstable();
+            changed.dedup();
+
+            for &i in &changed {
+                input[i].point += I16Vec2::new(rng.i16(-1..=1), rng.i16(-1..=1));
+            }
+
+            let before = input.clone();
+            let touched = sort_changed_by_curve(&mut input, &changed, Hilbert, Global).unwrap();
+
+            assert!(input.is_sorted_by_key(|x| Hilbert.key(x.point)));
+            assert!(touched.contains(&changed[0]));
+
+            // only the touched range was reordered
+            assert_eq!(input[..touched.start], before[..touched.start]);
+            assert_eq!(input[touched.end..], before[touched.end..]);
+
+            let mut sorted = input[touched.clone()].to_vec();
+            let mut expected = before[touched].to_vec();
+            sorted.sort_by_key(|x| x.tag);
+            expected.sort_by_key(|x| x.tag);
+            assert_eq!(sorted, expected);
+        }
+
+        // neighbours changing at once, so they have to move past each other
+        for _ in 0..20 {
+            let start = rng.usize(..input.len() - 10);
+            let changed: Vec<_> = (start..start + 10).collect();
+
+            for &i in &changed {
+                input[i].point += I16Vec2::new(rng.i16(-3..=3), rng.i16(-3..=3));
+            }
+
+            sort_changed_by_curve(&mut input, &changed, Hilbert, Global).unwrap();
+            assert!(input.is_sorted_by_key(|x| Hilbert.key(x.point)));
+        }
+
+        // far too much change gives up
+        input.reverse();
+        let changed: Vec<_> = (0..input.len()).collect();
+        assert_eq!(
+            sort_changed_by_curve(&mut input, &changed, Hilbert, Global),
+            None
+        );
+    }
+
+    #[test]
+    #[cfg(feature = "parallel")]
+    fn test_par_sort_matches_serial() {
+        let mut rng = fastrand::Rng::with_seed(7);
+
+        // plenty of duplicate keys to check that the order stays stable
+        let input: Vec<_> = (0..10_000)
+            .map(|tag| Tagged {
+                point: I16Vec2::new(rng.i16(-20..20), rng.i16(-20..20)),
+                tag,
+            })
+            .collect();
+
+        let mut serial = input.clone();
+        sort_by_curve_comparison(&mut serial, Hilbert);
+
+        let mut parallel = input;
+        par_sort_by_curve(&mut parallel, Hilbert);
+
+        assert_eq!(serial, parallel);
+    }
+
+    /// Times the radix sort against the comparison sort around [`RADIX_THRESHOLD`]. Run with
+    /// `cargo test --release --lib bench_radix_threshold -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "benchmark, only meaningful in release builds"]
+    fn bench_radix_threshold() {
+        // the fastest of a few rounds, each sorting about a million elements
+        fn time(input: &[Tagged], sort: impl Fn(&mut [Tagged])) -> Duration {
+            let runs = (1 << 20) / u32::try_from(input.len()).unwrap();
+
+            (0..3)
+                .map(|_| {
+                    let start = Instant::now();
+
+                    for _ in 0..runs {
+                        let mut input = input.to_vec();
+                        sort(&mut input);
+                        std::hint::black_box(input);
+                    }
+
+                    start.elapsed() / runs
+                })
+                .min()
+                .unwrap()
+        }
+
+        let mut rng = fastrand::Rng::with_seed(19);
+
+        for len in [64, 256, 512, RADIX_THRESHOLD, 4096, 16_384, 65_536, 262_144] {
+            // clustered points leave the high bytes of the keys equal, so passes are skipped
+            for extent in [100, i16::MAX] {
+                let input: Vec<_> = (0..)
+                    .take(len)
+                    .map(|tag| Tagged {
+                        point: I16Vec2::new(rng.i16(-extent..extent), rng.i16(-extent..extent)),
+                        tag,
+                    })
+                    .collect();
+
+                let comparison = time(&input, |input| sort_by_curve_comparison(input, Hilbert));
+                let radix = time(&input, |input| sort_by_curve_radix(input, Hilbert, Global));
+
+                println!(
+                    "{len:>7} elements within {extent:>5}: comparison {comparison:>10.2?}, radix \
+                     {radix:>10.2?}"
+                );
+            }
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use bvh::{Aabb, Bvh, Data, Point};
+use bvh::{
+    Aabb, Bvh, Chebyshev, Circle, ConvexPolygon, Curve, Data, Difference, Euclidean, Framing,
+    Hilbert, Manhattan, Metric, Morton, Point, QueryShape, RowMajor, ViewWedge,
+};
 use glam::I16Vec2;
 use itertools::Itertools;
 use proptest::prelude::*;
 use std::borrow::Cow;
+use std::ops::Range;
 
 #[derive(Clone)]
 struct ChunkWithPackets<'a> {
@@ -40,6 +44,29 @@ impl Data for ChunkWithPackets<'_> {
     }
 }
 
+/// One chunk per location with its index as data, so ranges can be mapped back to a location.
+fn indexed_chunks(locations: &[I16Vec2]) -> Vec<ChunkWithPackets<'static>> {
+    locations
+        .iter()
+        .zip(0..=u8::MAX)
+        .map(|(&location, idx)| ChunkWithPackets {
+            location,
+            packets_data: Cow::Owned(vec![idx]),
+        })
+        .collect()
+}
+
+/// The sorted elements of `ranges`.
+fn sorted_elements(bvh: &Bvh<Vec<u8>>, ranges: impl IntoIterator<Item = Range<u32>>) -> Vec<u8> {
+    let mut elements: Vec<u8> = ranges
+        .into_iter()
+        .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+        .copied()
+        .collect();
+    elements.sort_unstable();
+    elements
+}
+
 #[test]
 fn test_local_packet() {
     let data = [1, 2, 3, 4];
@@ -384,6 +411,39 @@ proptest! {
     }
 }
 
+fn test_k_closest_returns_nearest_leaves(locations: &[I16Vec2], query_point: I16Vec2, k: usize) {
+    let mut chunks = indexed_chunks(locations);
+
+    let bvh = Bvh::build(&mut chunks, ());
+
+    let distance2 = |pos: I16Vec2| {
+        let difference = pos.as_ivec2() - query_point.as_ivec2();
+        difference.abs().as_uvec2().length_squared()
+    };
+
+    let mut expected: Vec<_> = locations.iter().copied().unique().map(distance2).collect();
+    expected.sort_unstable();
+    expected.truncate(k);
+
+    let retrieved: Vec<_> = bvh
+        .get_k_closest(query_point, k)
+        .into_iter()
+        .map(|range| {
+            let idx = bvh.elements()[range.start as usize];
+            distance2(locations[usize::from(idx)])
+        })
+        .collect();
+
+    assert_eq!(retrieved, expected, "failed for {query_point:?}");
+}
+
+proptest! {
+    #[test]
+    fn prop_k_closest_returns_nearest_leaves(locations in proptest::collection::vec(arb_i16vec2(), 0..100), query_point in arb_i16vec2(), k in 0usize..10) {
+        test_k_closest_returns_nearest_leaves(&locations, query_point, k);
+    }
+}
+
 #[test]
 fn test_query_point_edge_case() {
     let mut chunks = vec![
@@ -479,6 +539,776 @@ proptest! {
     }
 }
 
+proptest! {
+    #[test]
+    fn prop_query_aabb_iter_matches_get_in(
+        mut chunks in proptest::collection::vec(arb_chunk_with_packets(), 0..100),
+        min in arb_i16vec2(),
+        max in arb_i16vec2(),
+    ) {
+        let query_aabb = Aabb::new(min.min(max), min.max(max));
+
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let expected: Vec<u8> = bvh.get_in_slices(query_aabb).into_iter().flatten().copied().collect();
+        let retrieved: Vec<u8> = bvh.get_in_slices_iter(query_aabb).flatten().copied().collect();
+        assert_eq!(&retrieved, &expected);
+
+        let bvh = bvh.into_bytes();
+        let retrieved: Vec<u8> = bvh.get_in_slices_bytes_iter(query_aabb).flatten().collect();
+        assert_eq!(retrieved, expected);
+        assert_eq!(bvh.bytes_in(query_aabb), expected.len());
+
+        let mut slices = Vec::new();
+        bvh.get_in_io_slices(query_aabb, &mut slices);
+        let retrieved: Vec<u8> = slices.iter().flat_map(|slice| slice.iter().copied()).collect();
+        assert_eq!(retrieved, expected);
+
+        let mut written = Vec::new();
+        assert_eq!(bvh.write_in(query_aabb, &mut written).unwrap(), expected.len());
+        assert_eq!(written, expected);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_query_radius_returns_correct_packets(
+        mut chunks in proptest::collection::vec(arb_chunk_with_packets(), 0..100),
+        center in arb_i16vec2(),
+        radius in 0u16..20_000,
+    ) {
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let radius2 = u32::from(radius).pow(2);
+
+        let mut expected_packets: Vec<u8> = chunks.iter()
+            .filter(|chunk| {
+                let difference = chunk.location.as_ivec2() - center.as_ivec2();
+                difference.abs().as_uvec2().length_squared() <= radius2
+            })
+            .flat_map(|chunk| chunk.packets_data.iter().copied())
+            .collect();
+
+        expected_packets.sort_unstable();
+
+        let mut retrieved_packets: Vec<u8> = bvh.get_within_radius(center, radius)
+            .into_iter()
+            .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+            .copied()
+            .collect();
+
+        retrieved_packets.sort_unstable();
+
+        assert_eq!(retrieved_packets, expected_packets);
+    }
+}
+
+fn test_metric_queries<M: Metric>(
+    locations: &[I16Vec2],
+    query_point: I16Vec2,
+    radius: u16,
+    metric: M,
+) {
+    let mut chunks = indexed_chunks(locations);
+
+    let bvh = Bvh::build(&mut chunks, ());
+
+    let expected_closest = locations
+        .iter()
+        .map(|&location| metric.distance(location, query_point))
+        .min();
+
+    let closest = bvh.get_closest_with(query_point, metric).map(|range| {
+        let idx = bvh.elements()[range.start as usize];
+        metric.distance(locations[usize::from(idx)], query_point)
+    });
+
+    assert_eq!(closest, expected_closest);
+
+    let radius_dist = metric.radius(radius);
+
+    let mut expected_within: Vec<u8> = chunks
+        .iter()
+        .filter(|chunk| metric.distance(chunk.location, query_point) <= radius_dist)
+        .flat_map(|chunk| chunk.packets_data.iter().copied())
+        .collect();
+    expected_within.sort_unstable();
+
+    let mut within: Vec<u8> = bvh
+        .get_within_radius_with(query_point, radius, metric)
+        .into_iter()
+        .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+        .copied()
+        .collect();
+    within.sort_unstable();
+
+    assert_eq!(within, expected_within);
+}
+
+proptest! {
+    #[test]
+    fn prop_metric_queries(
+        locations in proptest::collection::vec(arb_i16vec2(), 0..100),
+        query_point in arb_i16vec2(),
+        radius in 0u16..20_000,
+    ) {
+        test_metric_queries(&locations, query_point, radius, Euclidean);
+        test_metric_queries(&locations, query_point, radius, Chebyshev);
+        test_metric_queries(&locations, query_point, radius, Manhattan);
+    }
+}
+
+fn arb_aabb() -> impl Strategy<Value = Aabb> {
+    (arb_i16vec2(), arb_i16vec2()).prop_map(|(a, b)| Aabb::new(a.min(b), a.max(b)))
+}
+
+proptest! {
+    #[test]
+    fn prop_query_batch_matches_single_queries(
+        mut chunks in proptest::collection::vec(arb_chunk_with_packets(), 0..100),
+        queries in proptest::collection::vec(arb_aabb(), 0..20),
+    ) {
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let expected: Vec<Vec<_>> = queries
+            .iter()
+            .map(|&query| bvh.get_in_iter(query).collect())
+            .collect();
+
+        assert_eq!(bvh.get_in_batch(&queries), expected);
+
+        // stale contents are overwritten
+        let mut out = vec![vec![0..1]; 30];
+        bvh.get_in_batch_into(&queries, &mut out);
+        assert_eq!(out, expected);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_closest_where_skips_rejected(
+        locations in proptest::collection::vec(arb_i16vec2(), 0..100),
+        query_point in arb_i16vec2(),
+        modulo in 1u8..5,
+    ) {
+        let mut chunks = indexed_chunks(&locations);
+
+        let bvh = Bvh::build(&mut chunks, ());
+
+        // a leaf is accepted if any chunk at its location is
+        let predicate = |_: I16Vec2, data: &[u8]| data.iter().any(|idx| idx % modulo == 0);
+
+        let expected = locations
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| u8::try_from(idx).unwrap() % modulo == 0)
+            .map(|(_, &location)| Euclidean.distance(location, query_point))
+            .min();
+
+        let closest = bvh.get_closest_where_slice(query_point, predicate);
+        assert!(closest.is_none_or(|data| predicate(I16Vec2::ZERO, data)));
+
+        let closest = closest.map(|data| Euclidean.distance(locations[usize::from(data[0])], query_point));
+        assert_eq!(closest, expected);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_raycast_matches_cells(
+        mut chunks in proptest::collection::vec(arb_chunk_with_packets(), 0..100),
+        origin in (-20_000.0f32..20_000.0, -20_000.0f32..20_000.0),
+        target in (-20_000.0f32..20_000.0, -20_000.0f32..20_000.0),
+    ) {
+        let origin = glam::Vec2::from(origin);
+        let direction = glam::Vec2::from(target) - origin;
+
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let mut expected: Vec<_> = chunks
+            .iter()
+            .map(|chunk| chunk.location)
+            .unique()
+            .filter_map(|location| {
+                let t = Aabb::point(location).ray_entry(origin, direction, 1.0)?;
+                Some((location, t))
+            })
+            .collect();
+
+        expected.sort_by_key(|&(location, _)| (location.x, location.y));
+
+        let hits = bvh.segment(origin, glam::Vec2::from(target));
+
+        assert!(hits.iter().tuple_windows().all(|(a, b)| a.t <= b.t));
+
+        let mut retrieved: Vec<_> = hits.iter().map(|hit| (hit.point, hit.t)).collect();
+        retrieved.sort_by_key(|&(location, _)| (location.x, location.y));
+
+        assert_eq!(retrieved, expected);
+    }
+}
+
+fn test_query_shape_returns_correct_packets(
+    chunks: &mut [ChunkWithPackets<'_>],
+    shape: &impl QueryShape,
+) {
+    let bvh = Bvh::build(chunks, ());
+
+    let mut expected_packets: Vec<u8> = chunks
+        .iter()
+        .filter(|chunk| shape.contains_point(chunk.location))
+        .flat_map(|chunk| chunk.packets_data.iter().copied())
+        .collect();
+    expected_packets.sort_unstable();
+
+    let mut retrieved_packets: Vec<u8> = bvh.get_in_slices_iter(shape).flatten().copied().collect();
+    retrieved_packets.sort_unstable();
+
+    assert_eq!(retrieved_packets, expected_packets);
+
+    assert_eq!(bvh.count_in(shape) as usize, expected_packets.len());
+    assert_eq!(bvh.bytes_in(shape), expected_packets.len());
+}
+
+fn arb_small_i16vec2() -> impl Strategy<Value = I16Vec2> {
+    (-100i16..100, -100i16..100).prop_map(|(x, y)| I16Vec2::new(x, y))
+}
+
+proptest! {
+    #[test]
+    fn prop_query_shapes_return_correct_packets(
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), arb_packets_data()).prop_map(|(location, data)| ChunkWithPackets {
+                location,
+                packets_data: Cow::Owned(data),
+            }),
+            0..100,
+        ),
+        origin in (-100.0f32..100.0, -100.0f32..100.0),
+        yaw in -4.0f32..4.0,
+        fov in 0.1f32..6.2,
+        range in 0.0f32..150.0,
+        circle_centre in arb_small_i16vec2(),
+        outer in 0u16..150,
+        inner in 0u16..150,
+        centre in (-100.0f32..100.0, -100.0f32..100.0),
+        radius in 1.0f32..100.0,
+        angles in proptest::collection::vec(0.0f32..std::f32::consts::TAU, 3..8),
+
+    ) {
+        let wedge = ViewWedge::new(glam::Vec2::from(origin), yaw, fov, range);
+        test_query_shape_returns_correct_packets(&mut chunks, &wedge);
+
+        let annulus = Difference::new(
+            Circle::new(circle_centre, outer),
+            Circle::new(circle_centre, inner),
+        );
+        test_query_shape_returns_correct_packets(&mut chunks, &annulus);
+
+        // points on a circle are always convex
+        let vertices = angles
+            .into_iter()
+            .sorted_by(f32::total_cmp)
+            .map(|angle| glam::Vec2::from(centre) + glam::Vec2::from_angle(angle) * radius);
+
+        let polygon = ConvexPolygon::new(vertices);
+        test_query_shape_returns_correct_packets(&mut chunks, &polygon);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_query_leaves_match_points(
+        mut chunks in proptest::collection::vec(arb_chunk_with_packets(), 0..100),
+        query_aabb in arb_aabb(),
+    ) {
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let leaves: Vec<_> = bvh
+            .iter_leaves()
+            .map(|(point, data)| (point, data.to_vec()))
+            .collect();
+
+        // one leaf per distinct location, in the same order as the elements
+        assert_eq!(leaves.iter().map(|(point, _)| point).unique().count(), leaves.len());
+        let data: Vec<u8> = leaves.iter().flat_map(|(_, data)| data.iter().copied()).collect();
+        assert_eq!(data, bvh.elements());
+
+        for (point, data) in &leaves {
+            let mut expected: Vec<u8> = chunks
+                .iter()
+                .filter(|chunk| chunk.location == *point)
+                .flat_map(|chunk| chunk.packets_data.iter().copied())
+                .collect();
+            expected.sort_unstable();
+
+            let mut data = data.clone();
+            data.sort_unstable();
+            assert_eq!(data, expected);
+        }
+
+        let expected: Vec<_> = leaves
+            .iter()
+            .filter(|(point, _)| query_aabb.contains_point(*point))
+            .cloned()
+            .collect();
+        let retrieved: Vec<_> = bvh
+            .get_in_leaves(query_aabb)
+            .map(|(point, data)| (point, data.to_vec()))
+            .collect();
+        assert_eq!(&retrieved, &expected);
+
+        let bvh = bvh.into_bytes();
+        let retrieved: Vec<_> = bvh
+            .get_in_leaves_bytes(query_aabb)
+            .map(|(point, data)| (point, data.to_vec()))
+            .collect();
+        assert_eq!(retrieved, expected);
+        assert_eq!(bvh.iter_leaves_bytes().count(), leaves.len());
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_diff_matches_leaves(
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), arb_packets_data()).prop_map(|(location, data)| ChunkWithPackets {
+                location,
+                packets_data: Cow::Owned(data),
+            }),
+            0..100,
+        ),
+        prev_centre in arb_small_i16vec2(),
+        prev_radius in 0u16..100,
+        cur_centre in arb_small_i16vec2(),
+        cur_radius in 0u16..100,
+    ) {
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let prev = Circle::new(prev_centre, prev_radius);
+        let cur = Circle::new(cur_centre, cur_radius);
+
+        let diff = bvh.get_in_diff(prev, cur);
+
+        let expected_entered: Vec<_> = bvh.get_in_leaf_ranges(Difference::new(cur, prev)).collect();
+        let expected_left: Vec<_> = bvh.get_in_leaf_ranges(Difference::new(prev, cur)).collect();
+
+        assert_eq!(diff.entered, expected_entered);
+        assert_eq!(diff.left, expected_left);
+
+        // no change
+        let mut diff = diff;
+        bvh.get_in_diff_into(cur, cur, &mut diff);
+        assert!(diff.entered.is_empty());
+        assert!(diff.left.is_empty());
+    }
+}
+
+fn test_self_join<M: Metric>(locations: &[I16Vec2], distance: u16, metric: M) {
+    let mut chunks: Vec<_> = locations
+        .iter()
+        .map(|&location| ChunkWithPackets {
+            location,
+            packets_data: Cow::Borrowed(&[1]),
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut chunks, ());
+
+    // leaves in Hilbert order
+    let points: Vec<_> = bvh.iter_leaf_ranges().map(|(point, _)| point).collect();
+
+    let expected: Vec<_> = points
+        .iter()
+        .tuple_combinations()
+        .filter(|&(&a, &b)| metric.distance(a, b) <= metric.radius(distance))
+        .map(|(&a, &b)| (a, b))
+        .sorted_by_key(|&(a, b)| (a.x, a.y, b.x, b.y))
+        .collect();
+
+    let mut pairs = Vec::new();
+    bvh.self_join_within_with(distance, metric, |(a, range_a), (b, range_b)| {
+        assert_eq!(
+            bvh.get_in_leaf_ranges(Aabb::point(a)).next(),
+            Some((a, range_a))
+        );
+        assert_eq!(
+            bvh.get_in_leaf_ranges(Aabb::point(b)).next(),
+            Some((b, range_b))
+        );
+        pairs.push((a, b));
+    });
+
+    // every pair is reported once and in Hilbert order
+    pairs.sort_by_key(|&(a, b)| (a.x, a.y, b.x, b.y));
+    assert_eq!(pairs, expected);
+}
+
+proptest! {
+    #[test]
+    fn prop_self_join_matches_brute_force(
+        locations in proptest::collection::vec(arb_small_i16vec2(), 0..100),
+        distance in 0u16..60,
+    ) {
+        test_self_join(&locations, distance, Euclidean);
+        test_self_join(&locations, distance, Chebyshev);
+        test_self_join(&locations, distance, Manhattan);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_join_matches_single_queries(
+        mut viewers in proptest::collection::vec(arb_chunk_with_packets(), 0..100),
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), arb_packets_data()).prop_map(|(location, data)| ChunkWithPackets {
+                location,
+                packets_data: Cow::Owned(data),
+            }),
+            0..100,
+        ),
+        viewer_scale in 1i16..300,
+        half_extent in (0u16..60, 0u16..60),
+    ) {
+        // spread the viewers over a larger area than the chunks
+        for viewer in &mut viewers {
+            viewer.location /= I16Vec2::splat(viewer_scale);
+        }
+
+        let viewers = Bvh::build(&mut viewers, ());
+        let chunks = Bvh::build(&mut chunks, ()).into_bytes();
+
+        let half_extent = glam::U16Vec2::from(half_extent);
+
+        let expected: Vec<_> = viewers
+            .iter_leaf_ranges()
+            .map(|(point, range)| {
+                let min = point.as_ivec2() - half_extent.as_ivec2();
+                let max = point.as_ivec2() + half_extent.as_ivec2();
+                let query = Aabb::new(
+                    min.max(glam::IVec2::splat(i16::MIN.into())).as_i16vec2(),
+                    max.min(glam::IVec2::splat(i16::MAX.into())).as_i16vec2(),
+                );
+
+                ((point, range), chunks.get_in_iter(query).collect::<Vec<_>>())
+            })
+            .collect();
+
+        let mut joined = Vec::new();
+        viewers.join_in(&chunks, half_extent, |leaf, ranges| {
+            joined.push((leaf, ranges.to_vec()));
+        });
+
+        assert_eq!(joined, expected);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_closest_approx_within_tolerance(
+        locations in proptest::collection::vec(arb_small_i16vec2(), 1..30),
+        query_point in arb_small_i16vec2(),
+        max_visits in 0usize..20,
+        epsilon in 0.0f32..2.0,
+    ) {
+        let mut chunks = indexed_chunks(&locations);
+
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let closest = locations
+            .iter()
+            .map(|&location| Euclidean.distance(location, query_point))
+            .min()
+            .unwrap();
+
+        let distance_of = |range: std::ops::Range<u32>| {
+            let idx = bvh.elements()[range.start as usize];
+            Euclidean.distance(locations[usize::from(idx)], query_point)
+        };
+
+        let exact = bvh.get_closest_approx(query_point, usize::MAX, 0.0).unwrap();
+        assert!(exact.exact);
+        assert_eq!(distance_of(exact.range), closest);
+
+        let approx = bvh.get_closest_approx(query_point, usize::MAX, epsilon).unwrap();
+        let bound = f64::from(closest) * (1.0 + f64::from(epsilon)).powi(2);
+        assert!(f64::from(distance_of(approx.range.clone())) <= bound);
+        if approx.exact {
+            assert_eq!(distance_of(approx.range), closest);
+        }
+
+        let bounded = bvh.get_closest_approx(query_point, max_visits, epsilon).unwrap();
+        if bounded.exact {
+            assert_eq!(distance_of(bounded.range), closest);
+        }
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_nearest_first_respects_budget(
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), arb_packets_data()).prop_map(|(location, data)| ChunkWithPackets {
+                location,
+                packets_data: Cow::Owned(data),
+            }),
+            0..100,
+        ),
+        query_aabb in (arb_small_i16vec2(), arb_small_i16vec2())
+            .prop_map(|(a, b)| Aabb::new(a.min(b), a.max(b))),
+        centre in arb_small_i16vec2(),
+        max_bytes in 0usize..200,
+    ) {
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let result = bvh.get_in_nearest_bytes(query_aabb, centre, max_bytes);
+
+        let taken: u32 = result.ranges.iter().map(|range| range.end - range.start).sum();
+        assert!(taken as usize <= max_bytes);
+        assert_eq!(taken + result.dropped, bvh.count_in(query_aabb));
+
+        // every leaf that was cut off is at least as far away as every leaf that was returned
+        let (returned, dropped): (Vec<_>, Vec<_>) = bvh
+            .get_in_leaf_ranges(query_aabb)
+            .filter(|(_, range)| !range.is_empty())
+            .partition(|(_, range)| {
+                result
+                    .ranges
+                    .iter()
+                    .any(|returned| returned.start <= range.start && range.end <= returned.end)
+            });
+
+        let furthest_returned = returned
+            .iter()
+            .map(|&(point, _)| Euclidean.distance(point, centre))
+            .max();
+        let closest_dropped = dropped
+            .iter()
+            .map(|&(point, _)| Euclidean.distance(point, centre))
+            .min();
+
+        if let (Some(furthest_returned), Some(closest_dropped)) = (furthest_returned, closest_dropped) {
+            assert!(furthest_returned <= closest_dropped);
+        }
+
+        // ranges come out closest first
+        let distances: Vec<Vec<u32>> = result
+            .ranges
+            .iter()
+            .map(|returned| {
+                bvh.get_in_leaf_ranges(query_aabb)
+                    .filter(|(_, range)| !range.is_empty())
+                    .filter(|(_, range)| returned.start <= range.start && range.end <= returned.end)
+                    .map(|(point, _)| Euclidean.distance(point, centre))
+                    .collect()
+            })
+            .collect();
+
+        for (a, b) in distances.iter().tuple_windows() {
+            assert!(a.iter().max() <= b.iter().min());
+        }
+
+        let bvh = bvh.into_bytes();
+        assert_eq!(bvh.get_in_nearest_bytes(query_aabb, centre, max_bytes), result);
+    }
+}
+
+fn test_buckets<M: Metric>(
+    chunks: &mut [ChunkWithPackets<'_>],
+    centre: I16Vec2,
+    thresholds: &[u16],
+    metric: M,
+) {
+    let bvh = Bvh::build(chunks, ());
+
+    let mut buckets = Vec::new();
+    bvh.get_in_buckets_into(centre, thresholds, metric, &mut buckets);
+    assert_eq!(buckets.len(), thresholds.len());
+
+    for (bucket, ranges) in buckets.iter().enumerate() {
+        let min = bucket
+            .checked_sub(1)
+            .map(|previous| metric.radius(thresholds[previous]));
+        let max = metric.radius(thresholds[bucket]);
+
+        let mut expected: Vec<u8> = chunks
+            .iter()
+            .filter(|chunk| {
+                let dist = metric.distance(chunk.location, centre);
+                min.is_none_or(|min| min < dist) && dist <= max
+            })
+            .flat_map(|chunk| chunk.packets_data.iter().copied())
+            .collect();
+        expected.sort_unstable();
+
+        // merged within the bucket
+        for (a, b) in ranges.iter().tuple_windows() {
+            assert!(a.end < b.start);
+        }
+
+        let mut retrieved: Vec<u8> = ranges
+            .iter()
+            .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+            .copied()
+            .collect();
+        retrieved.sort_unstable();
+
+        assert_eq!(retrieved, expected);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_buckets_match_distances(
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), arb_packets_data()).prop_map(|(location, data)| ChunkWithPackets {
+                location,
+                packets_data: Cow::Owned(data),
+            }),
+            0..100,
+        ),
+        centre in arb_small_i16vec2(),
+        thresholds in proptest::collection::vec(0u16..150, 0..5),
+    ) {
+        let thresholds: Vec<_> = thresholds.into_iter().sorted().collect();
+
+        test_buckets(&mut chunks, centre, &thresholds, Euclidean);
+        test_buckets(&mut chunks, centre, &thresholds, Chebyshev);
+        test_buckets(&mut chunks, centre, &thresholds, Manhattan);
+    }
+}
+
+proptest! {
+    #[test]
+    fn prop_refit_matches_moved_points(
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), arb_packets_data()).prop_map(|(location, data)| ChunkWithPackets {
+                location,
+                packets_data: Cow::Owned(data),
+            }),
+            1..100,
+        ),
+        moves in proptest::collection::vec((any::<proptest::sample::Index>(), -5i16..=5, -5i16..=5), 0..50),
+        query_aabb in (arb_small_i16vec2(), arb_small_i16vec2())
+            .prop_map(|(a, b)| Aabb::new(a.min(b), a.max(b))),
+    ) {
+        let mut bvh = Bvh::build(&mut chunks, ());
+
+        let mut leaves: Vec<_> = bvh.iter_leaf_ranges().collect();
+
+        let updates: Vec<_> = moves
+            .iter()
+            .map(|(leaf, dx, dy)| {
+                let ptr = leaf.index(leaves.len());
+                let point = leaves[ptr].0 + I16Vec2::new(*dx, *dy);
+                leaves[ptr].0 = point;
+                (u32::try_from(ptr).unwrap(), point)
+            })
+            .collect();
+
+        bvh.refit(&updates);
+
+        assert_eq!(bvh.iter_leaf_ranges().collect::<Vec<_>>(), leaves);
+
+        let mut expected: Vec<u8> = leaves
+            .iter()
+            .filter(|(point, _)| query_aabb.contains_point(*point))
+            .flat_map(|(_, range)| &bvh.elements()[range.start as usize..range.end as usize])
+            .copied()
+            .collect();
+        expected.sort_unstable();
+
+        let mut retrieved: Vec<u8> = bvh.get_in_slices_iter(query_aabb).flatten().copied().collect();
+        retrieved.sort_unstable();
+
+        assert_eq!(retrieved, expected);
+    }
+}
+
+/// Splits a buffer written by `gather_in_framed` back into the data of each leaf.
+fn split_framed(mut buf: &[u8], framing: Framing) -> Vec<Vec<u8>> {
+    let mut leaves = Vec::new();
+
+    while !buf.is_empty() {
+        let len = match framing {
+            Framing::None => buf.len(),
+            Framing::U32 => {
+                let (len, rest) = buf.split_at(4);
+                buf = rest;
+                u32::from_be_bytes(len.try_into().unwrap()) as usize
+            }
+            Framing::VarInt => {
+                let mut len = 0;
+                let mut shift = 0;
+                loop {
+                    let byte = buf[0];
+                    buf = &buf[1..];
+                    len |= usize::from(byte & 0x7F) << shift;
+                    shift += 7;
+                    if byte & 0x80 == 0 {
+                        break len;
+                    }
+                }
+            }
+        };
+
+        let (data, rest) = buf.split_at(len);
+        leaves.push(data.to_vec());
+        buf = rest;
+    }
+
+    leaves
+}
+
+proptest! {
+    #[test]
+    fn prop_gather_matches_leaves(
+        mut chunks in proptest::collection::vec(
+            (arb_small_i16vec2(), proptest::collection::vec(any::<u8>(), 0..300))
+                .prop_map(|(location, data)| ChunkWithPackets {
+                    location,
+                    packets_data: Cow::Owned(data),
+                }),
+            0..100,
+        ),
+        min in arb_small_i16vec2(),
+        max in arb_small_i16vec2(),
+    ) {
+        let query_aabb = Aabb::new(min.min(max), min.max(max));
+
+        let bvh = Bvh::build(&mut chunks, ());
+
+        let expected: Vec<u8> = bvh.get_in_slices_iter(query_aabb).flatten().copied().collect();
+        let expected_leaves: Vec<Vec<u8>> = bvh
+            .get_in_leaves(query_aabb)
+            .filter(|(_, data)| !data.is_empty())
+            .map(|(_, data)| data.to_vec())
+            .collect();
+
+        // existing contents are kept
+        let mut out = bytes::BytesMut::from(&b"prefix"[..]);
+        assert_eq!(bvh.gather_in(query_aabb, &mut out), expected.len());
+        assert_eq!(&out[..6], b"prefix");
+        assert_eq!(&out[6..], &expected[..]);
+
+        for framing in [Framing::None, Framing::U32, Framing::VarInt] {
+            let mut out = bytes::BytesMut::new();
+            assert_eq!(bvh.gather_in_framed(query_aabb, &mut out, framing), out.len());
+
+            if framing == Framing::None {
+                assert_eq!(&out[..], &expected[..]);
+            } else {
+                assert_eq!(split_framed(&out, framing), expected_leaves);
+            }
+        }
+
+        let bvh = bvh.into_bytes();
+        let mut out = bytes::BytesMut::new();
+        bvh.gather_in_framed(query_aabb, &mut out, Framing::VarInt);
+        assert_eq!(split_framed(&out, Framing::VarInt), expected_leaves);
+    }
+}
+
 fn test_build_bvh_with_single_packet(packet: &ChunkWithPackets<'_>) {
     let bvh = Bvh::build(&mut [packet.clone()], ());
 
@@ -520,3 +1350,106 @@ proptest! {
         test_build_bvh_with_single_packet(&packet);
     }
 }
+
+fn test_curve_queries<C: Curve>(
+    locations: &[I16Vec2],
+    query: Aabb,
+    query_point: I16Vec2,
+    curve: C,
+) {
+    let mut chunks = indexed_chunks(locations);
+
+    let bvh = Bvh::build_with_curve(&mut chunks, curve, ());
+
+    // the input is left in curve order
+    assert!(chunks.is_sorted_by_key(|chunk| curve.key(chunk.location)));
+
+    let mut expected: Vec<u8> = chunks
+        .iter()
+        .filter(|chunk| query.contains_point(chunk.location))
+        .flat_map(|chunk| chunk.packets_data.iter().copied())
+        .collect();
+    expected.sort_unstable();
+
+    assert_eq!(sorted_elements(&bvh, bvh.get_in(query)), expected);
+
+    let expected_closest = locations
+        .iter()
+        .map(|&location| Euclidean.distance(location, query_point))
+        .min();
+
+    let closest = bvh.get_closest(query_point).map(|range| {
+        let idx = bvh.elements()[range.start as usize];
+        Euclidean.distance(locations[usize::from(idx)], query_point)
+    });
+
+    assert_eq!(closest, expected_closest);
+}
+
+proptest! {
+    #[test]
+    fn prop_curve_queries(
+        locations in proptest::collection::vec(arb_i16vec2(), 0..100),
+        query in arb_aabb(),
+        query_point in arb_i16vec2(),
+    ) {
+        test_curve_queries(&locations, query, query_point, Hilbert);
+        test_curve_queries(&locations, query, query_point, Morton);
+        test_curve_queries(&locations, query, query_point, RowMajor);
+    }
+}
+
+fn test_sah_queries(locations: &[I16Vec2], query: Aabb, query_point: I16Vec2, radius: u16) {
+    let mut chunks = indexed_chunks(locations);
+
+    let implicit = Bvh::build(&mut chunks, ());
+    let sah = Bvh::build_sah(&mut chunks, ());
+
+    assert!(implicit.is_implicit());
+    assert!(locations.len() < 2 || !sah.is_implicit());
+
+    assert_eq!(
+        sorted_elements(&sah, sah.get_in(query)),
+        sorted_elements(&implicit, implicit.get_in(query))
+    );
+    assert_eq!(sah.count_in(query), implicit.count_in(query));
+    assert_eq!(
+        sorted_elements(&sah, sah.get_within_radius(query_point, radius)),
+        sorted_elements(&implicit, implicit.get_within_radius(query_point, radius))
+    );
+
+    let closest_distance = |bvh: &Bvh<Vec<u8>>| {
+        bvh.get_closest(query_point).map(|range| {
+            let idx = bvh.elements()[range.start as usize];
+            Euclidean.distance(locations[usize::from(idx)], query_point)
+        })
+    };
+
+    assert_eq!(closest_distance(&sah), closest_distance(&implicit));
+
+    // both trees hold the same leaves
+    let leaves = |bvh: &Bvh<Vec<u8>>| -> Vec<([i16; 2], Vec<u8>)> {
+        bvh.iter_leaves()
+            .map(|(point, data)| {
+                let mut data = data.to_vec();
+                data.sort_unstable();
+                (point.to_array(), data)
+            })
+            .sorted()
+            .collect()
+    };
+
+    assert_eq!(leaves(&sah), leaves(&implicit));
+}
+
+proptest! {
+    #[test]
+    fn prop_sah_queries_match_implicit(
+        locations in proptest::collection::vec(arb_i16vec2(), 0..100),
+        query in arb_aabb(),
+        query_point in arb_i16vec2(),
+        radius in 0u16..20_000,
+    ) {
+        test_sah_queries(&locations, query, query_point, radius);
+    }
+}
@@ -36,6 +36,18 @@ impl Data for Player {
     }
 }
 
+/// A 16x16 grid of players around the origin, `spacing` apart, with ids in row order.
+fn player_grid(spacing: i16) -> Vec<Player> {
+    (-8..8)
+        .cartesian_product(-8..8)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x * spacing, y * spacing),
+            id,
+        })
+        .collect()
+}
+
 #[test]
 fn test_local_player() {
     let id = 123;
@@ -377,3 +389,659 @@ fn test_build_bvh_with_non_power_of_2_players() {
 
     assert_eq!(s, expected);
 }
+
+#[test]
+fn test_k_closest_players() {
+    let mut input: Vec<_> = (0..5)
+        .map(|i| Player {
+            location: I16Vec2::new(i, i),
+            id: u32::try_from(i).unwrap() + 1,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let result = bvh.get_k_closest_slices(I16Vec2::new(3, 3), 3);
+    assert_eq!(result[0], &[4]);
+
+    let mut rest: Vec<_> = result[1..].iter().flat_map(|x| x.iter().copied()).collect();
+    rest.sort_unstable();
+    assert_eq!(rest, [3, 5]);
+
+    // asking for more than there are returns everything
+    let result = bvh.get_k_closest_slices(I16Vec2::new(-10, -10), 10);
+    let ids: Vec<_> = result.into_iter().flatten().copied().collect();
+    assert_eq!(ids, [1, 2, 3, 4, 5]);
+
+    // `k` is clamped to the leaf count before anything is allocated
+    assert_eq!(bvh.get_k_closest(I16Vec2::new(0, 0), usize::MAX).len(), 5);
+
+    assert!(bvh.get_k_closest(I16Vec2::new(0, 0), 0).is_empty());
+}
+
+#[test]
+fn test_query_many_disjoint_ranges() {
+    let mut input: Vec<_> = (0..128)
+        .cartesian_product(0..128)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let query = Aabb::new(I16Vec2::new(3, 5), I16Vec2::new(77, 61));
+
+    let mut ranges = Vec::new();
+    bvh.get_in_into(query, &mut ranges);
+
+    // more than `get_in` can hold
+    assert!(ranges.len() > 32, "only {} ranges", ranges.len());
+    assert_eq!(ranges, bvh.get_in_iter(query).collect::<Vec<_>>());
+
+    // merged ranges never touch
+    assert!(ranges.iter().tuple_windows().all(|(a, b)| a.end < b.start));
+
+    let mut ids: Vec<_> = bvh.get_in_slices_iter(query).flatten().copied().collect();
+    ids.sort_unstable();
+
+    let mut expected: Vec<_> = input
+        .iter()
+        .filter(|player| query.contains_point(player.location))
+        .map(|player| player.id)
+        .collect();
+    expected.sort_unstable();
+
+    assert_eq!(ids, expected);
+}
+
+#[test]
+fn test_within_radius_players() {
+    let mut input: Vec<_> = (-20..20)
+        .cartesian_product(-20..20)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let center = I16Vec2::new(3, -2);
+    let radius = 7;
+
+    let ranges = bvh.get_within_radius(center, radius);
+    assert!(ranges.iter().tuple_windows().all(|(a, b)| a.end < b.start));
+
+    let mut ids: Vec<_> = ranges
+        .into_iter()
+        .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+        .copied()
+        .collect();
+    ids.sort_unstable();
+
+    let mut expected: Vec<_> = input
+        .iter()
+        .filter(|player| {
+            player
+                .location
+                .as_ivec2()
+                .distance_squared(center.as_ivec2())
+                <= 49
+        })
+        .map(|player| player.id)
+        .collect();
+    expected.sort_unstable();
+
+    assert_eq!(ids, expected);
+
+    // the corners of the enclosing square are not included
+    assert!(expected.len() < 15 * 15);
+}
+
+#[test]
+fn test_closest_player_where() {
+    let mut input: Vec<_> = (0..5)
+        .map(|i| Player {
+            location: I16Vec2::new(i, i),
+            id: u32::try_from(i).unwrap() + 1,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    // closest player that is not on the same team (odd ids)
+    let result = bvh.get_closest_where_slice(I16Vec2::new(3, 2), |_, ids| ids[0] % 2 == 1);
+    assert_eq!(result, Some([3].as_slice()));
+
+    let result = bvh.get_closest_where_slice(I16Vec2::new(3, 2), |point, _| point.x < 2);
+    assert_eq!(result, Some([2].as_slice()));
+
+    let result = bvh.get_closest_where_slice(I16Vec2::new(3, 3), |_, _| false);
+    assert_eq!(result, None);
+}
+
+#[test]
+fn test_segment_hits_players_in_order() {
+    let mut input: Vec<_> = (0..10)
+        .cartesian_product(0..3)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    // along the middle row, right to left
+    let hits = bvh.segment(glam::Vec2::new(7.5, 1.5), glam::Vec2::new(2.5, 1.5));
+    let points: Vec<_> = hits.iter().map(|hit| hit.point).collect();
+
+    assert_eq!(
+        points,
+        (2..=7)
+            .rev()
+            .map(|x| I16Vec2::new(x, 1))
+            .collect::<Vec<_>>()
+    );
+    assert!(hits.iter().tuple_windows().all(|(a, b)| a.t < b.t));
+    assert_eq!(hits.first().map(|hit| hit.t), Some(0.0));
+
+    for hit in &hits {
+        let ids = &bvh.elements()[hit.range.start as usize..hit.range.end as usize];
+        let player = input.iter().find(|player| player.id == ids[0]).unwrap();
+        assert_eq!(player.location, hit.point);
+    }
+
+    // stop at the first hit
+    let mut first = None;
+    bvh.raycast_each(glam::Vec2::new(-5.5, 2.5), glam::Vec2::X, 100.0, |hit| {
+        first = Some(hit.point);
+        std::ops::ControlFlow::Break(())
+    });
+    assert_eq!(first, Some(I16Vec2::new(0, 2)));
+
+    assert!(bvh
+        .raycast(glam::Vec2::new(-5.5, 2.5), glam::Vec2::NEG_X, 100.0)
+        .is_empty());
+}
+
+#[test]
+fn test_view_distance_grows() {
+    let mut input: Vec<_> = (-16..16)
+        .cartesian_product(-16..16)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let old = Aabb::new(I16Vec2::new(-8, -8), I16Vec2::new(8, 8));
+    let new = Aabb::new(I16Vec2::new(-10, -10), I16Vec2::new(10, 10));
+
+    let mut ids: Vec<_> = bvh
+        .get_in_difference(new, old)
+        .into_iter()
+        .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+        .copied()
+        .collect();
+    ids.sort_unstable();
+
+    let mut expected: Vec<_> = input
+        .iter()
+        .filter(|player| {
+            new.contains_point(player.location) && !old.contains_point(player.location)
+        })
+        .map(|player| player.id)
+        .collect();
+    expected.sort_unstable();
+
+    assert_eq!(ids.len(), 21 * 21 - 17 * 17);
+    assert_eq!(ids, expected);
+}
+
+#[test]
+fn test_count_in_players() {
+    let mut input: Vec<_> = (-16..16)
+        .cartesian_product(-16..16)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let query = Aabb::new(I16Vec2::new(-4, -3), I16Vec2::new(5, 6));
+    assert_eq!(bvh.count_in(query), 10 * 10);
+    assert_eq!(bvh.bytes_in(query), 10 * 10 * size_of::<EntityId>());
+
+    let everything = Aabb::new(I16Vec2::new(-100, -100), I16Vec2::new(100, 100));
+    assert_eq!(bvh.count_in(everything), 32 * 32);
+
+    let nothing = Aabb::new(I16Vec2::new(50, 50), I16Vec2::new(100, 100));
+    assert_eq!(bvh.count_in(nothing), 0);
+    assert_eq!(bvh.bytes_in(nothing), 0);
+}
+
+#[test]
+fn test_leaves_with_points() {
+    let mut input = player_grid(1);
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let locations: HashMap<_, _> = input
+        .iter()
+        .map(|player| (player.id, player.location))
+        .collect();
+
+    // leaves come out in the same order as the elements
+    let ids: Vec<_> = bvh
+        .iter_leaves()
+        .flat_map(|(point, ids)| {
+            assert_eq!(ids.len(), 1);
+            assert_eq!(locations[&ids[0]], point);
+            ids
+        })
+        .copied()
+        .collect();
+    assert_eq!(ids, bvh.elements());
+
+    let query = Aabb::new(I16Vec2::new(-3, -3), I16Vec2::new(2, 4));
+    let mut points: Vec<_> = bvh.get_in_leaves(query).map(|(point, _)| point).collect();
+    assert_eq!(points.len(), 6 * 8);
+
+    points.sort_unstable_by_key(|point| (point.x, point.y));
+    let expected: Vec<_> = (-3..=2)
+        .cartesian_product(-3..=4)
+        .map(|(x, y)| I16Vec2::new(x, y))
+        .collect();
+    assert_eq!(points, expected);
+}
+
+#[test]
+fn test_player_moves_diff() {
+    let mut input: Vec<_> = (-16..16)
+        .cartesian_product(-16..16)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    // move one chunk along +x with a view distance of 4
+    let prev = Aabb::new(I16Vec2::new(-4, -4), I16Vec2::new(4, 4));
+    let cur = Aabb::new(I16Vec2::new(-3, -4), I16Vec2::new(5, 4));
+
+    let diff = bvh.get_in_diff(prev, cur);
+
+    let mut entered: Vec<_> = diff.entered.iter().map(|(point, _)| *point).collect();
+    entered.sort_unstable_by_key(|point| point.y);
+    let expected: Vec<_> = (-4..=4).map(|y| I16Vec2::new(5, y)).collect();
+    assert_eq!(entered, expected);
+
+    let mut left: Vec<_> = diff.left.iter().map(|(point, _)| *point).collect();
+    left.sort_unstable_by_key(|point| point.y);
+    let expected: Vec<_> = (-4..=4).map(|y| I16Vec2::new(-4, y)).collect();
+    assert_eq!(left, expected);
+
+    for (point, range) in diff.entered.iter().chain(&diff.left) {
+        let ids = &bvh.elements()[range.start as usize..range.end as usize];
+        let player = input.iter().find(|player| player.id == ids[0]).unwrap();
+        assert_eq!(player.location, *point);
+    }
+}
+
+#[test]
+fn test_self_join_players() {
+    let mut input: Vec<_> = (-16..16)
+        .cartesian_product(-16..16)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let mut pairs = 0;
+    bvh.self_join_within(1, |(a, _), (b, _)| {
+        assert_eq!((a - b).abs().element_sum(), 1);
+        pairs += 1;
+    });
+
+    // horizontal and vertical neighbours
+    assert_eq!(pairs, 2 * 31 * 32);
+
+    let mut pairs = 0;
+    bvh.self_join_within_with(1, bvh::Chebyshev, |_, _| pairs += 1);
+
+    // plus both diagonals
+    assert_eq!(pairs, 2 * 31 * 32 + 2 * 31 * 31);
+}
+
+#[test]
+fn test_join_players_with_chunks() {
+    let mut players: Vec<_> = (0..64)
+        .map(|id: u16| Player {
+            location: I16Vec2::new(id.cast_signed() % 8 * 4, id.cast_signed() / 8 * 4),
+            id: u32::from(id),
+        })
+        .collect();
+
+    let mut chunks: Vec<_> = (-8..40)
+        .cartesian_product(-8..40)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let players = Bvh::build(&mut players, ());
+    let chunks = Bvh::build(&mut chunks, ());
+
+    let mut visited = 0;
+    players.join_in(&chunks, glam::U16Vec2::splat(2), |(point, _), ranges| {
+        let mut seen: Vec<_> = ranges
+            .iter()
+            .flat_map(|range| &chunks.elements()[range.start as usize..range.end as usize])
+            .copied()
+            .collect();
+        seen.sort_unstable();
+
+        let view = Aabb::new(point - I16Vec2::splat(2), point + I16Vec2::splat(2));
+        let mut expected: Vec<_> = chunks
+            .iter_leaves()
+            .filter(|(location, _)| view.contains_point(*location))
+            .flat_map(|(_, ids)| ids)
+            .copied()
+            .collect();
+        expected.sort_unstable();
+
+        assert_eq!(seen.len(), 5 * 5);
+        assert_eq!(seen, expected);
+        visited += 1;
+    });
+
+    assert_eq!(visited, 64);
+}
+
+#[test]
+fn test_closest_player_approx() {
+    let mut input: Vec<_> = (-16..16)
+        .cartesian_product(-16..16)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x * 4, y * 4),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let query = I16Vec2::new(9, -7);
+    let expected = input
+        .iter()
+        .find(|player| player.location == I16Vec2::new(8, -8))
+        .unwrap()
+        .id;
+
+    let closest = bvh.get_closest_approx(query, usize::MAX, 0.0).unwrap();
+    assert!(closest.exact);
+    assert_eq!(bvh.elements()[closest.range.start as usize], expected);
+
+    // a single visit cannot reach a leaf of this tree, so the answer is only a guess
+    let guess = bvh.get_closest_approx(query, 1, 0.0).unwrap();
+    assert!(!guess.exact);
+    assert_eq!(guess.range.len(), 1);
+
+    // a loose tolerance still returns a close player
+    let loose = bvh.get_closest_approx(query, usize::MAX, 1.0).unwrap();
+    let id = bvh.elements()[loose.range.start as usize];
+    let player = input.iter().find(|player| player.id == id).unwrap();
+    assert_le!(
+        (player.location - query).as_vec2().length(),
+        2.0 * 2.0_f32.sqrt()
+    );
+}
+
+#[test]
+fn test_nearest_first_budget() {
+    let mut input = player_grid(1);
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let query = Aabb::new(I16Vec2::new(-8, -8), I16Vec2::new(7, 7));
+
+    // the centre and its four direct neighbours
+    let result = bvh.get_in_nearest_bytes(query, I16Vec2::ZERO, 5 * size_of::<EntityId>() + 1);
+    assert_eq!(result.dropped, 16 * 16 - 5);
+
+    let mut points: Vec<_> = result
+        .ranges
+        .iter()
+        .flat_map(|range| &bvh.elements()[range.start as usize..range.end as usize])
+        .map(|id| {
+            input
+                .iter()
+                .find(|player| player.id == *id)
+                .unwrap()
+                .location
+        })
+        .collect();
+    points.sort_unstable_by_key(|point| (point.x, point.y));
+
+    assert_eq!(
+        points,
+        [
+            I16Vec2::new(-1, 0),
+            I16Vec2::new(0, -1),
+            I16Vec2::new(0, 0),
+            I16Vec2::new(0, 1),
+            I16Vec2::new(1, 0),
+        ]
+    );
+
+    let everything = bvh.get_in_nearest(query, I16Vec2::ZERO, u32::MAX);
+    assert_eq!(everything.dropped, 0);
+    assert_eq!(
+        everything
+            .ranges
+            .iter()
+            .map(|range| range.end - range.start)
+            .sum::<u32>(),
+        16 * 16
+    );
+}
+
+#[test]
+fn test_buckets_players() {
+    let mut input: Vec<_> = (-16..16)
+        .cartesian_product(-16..16)
+        .zip(0..)
+        .map(|((x, y), id)| Player {
+            location: I16Vec2::new(x, y),
+            id,
+        })
+        .collect();
+
+    let bvh = Bvh::build(&mut input, ());
+
+    let buckets = bvh.get_in_buckets(I16Vec2::ZERO, &[0, 1, 2]);
+
+    let counts: Vec<u32> = buckets
+        .iter()
+        .map(|ranges| ranges.iter().map(|range| range.end - range.start).sum())
+        .collect();
+
+    // the centre, its four direct neighbours and the 8 points at distance sqrt(2) and 2
+    assert_eq!(counts, [1, 4, 8]);
+
+    let empty = bvh.get_in_buckets(I16Vec2::ZERO, &[]);
+    assert!(empty.is_empty());
+}
+
+#[test]
+fn test_refit_moving_players() {
+    let mut input = player_grid(4);
+
+    let mut bvh = Bvh::build(&mut input, ());
+    let built = bvh.cost();
+
+    // every player takes one step along +x
+    let updates: Vec<_> = bvh
+        .iter_leaf_ranges()
+        .zip(0..)
+        .map(|((point, _), leaf)| (leaf, point + I16Vec2::X))
+        .collect();
+
+    // a uniform shift keeps every box the same size
+    assert_eq!(bvh.refit(&updates), built);
+
+    let query = Aabb::new(I16Vec2::new(1, 0), I16Vec2::new(1, 0));
+    let ids: Vec<_> = bvh
+        .get_in_slices(query)
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+    let expected = input
+        .iter()
+        .find(|player| player.location == I16Vec2::ZERO)
+        .unwrap()
+        .id;
+    assert_eq!(ids, [expected]);
+
+    // sending every other player to the opposite side makes the tree worse
+    let updates: Vec<_> = bvh
+        .iter_leaf_ranges()
+        .zip(0..)
+        .step_by(2)
+        .map(|((point, _), leaf)| (leaf, -point))
+        .collect();
+    assert!(bvh.refit(&updates) > built);
+}
+
+#[test]
+fn test_rebuild_moving_players() {
+    let mut input = player_grid(4);
+
+    let mut bvh = Bvh::build(&mut input, ());
+
+    for (tick, x) in (0..10).zip(0..) {
+        // a few players walk around, one joins and one leaves
+        let mut changed = Vec::new();
+
+        for (i, player) in input.iter_mut().enumerate() {
+            if player.id % 7 == 0 {
+                player.location += I16Vec2::new(1, -1);
+                changed.push(i);
+            }
+        }
+
+        if let Some(i) = input.iter().position(|player| player.id == tick) {
+            input.swap_remove(i);
+            changed.push(i);
+        }
+
+        input.push(Player {
+            location: I16Vec2::new(x, 0),
+            id: 1000 + tick,
+        });
+        changed.push(input.len() - 1);
+
+        let cost = bvh.rebuild(&mut input, &changed, ());
+        let built = Bvh::build(&mut input.clone(), ());
+
+        assert_eq!(cost, built.cost());
+        assert_eq!(bvh.elements(), built.elements());
+        assert_eq!(
+            bvh.iter_leaf_ranges().collect_vec(),
+            built.iter_leaf_ranges().collect_vec()
+        );
+    }
+}
+
+#[test]
+fn test_sah_two_armies() {
+    // two armies at opposite ends of the map
+    let mut input: Vec<_> = (0..20)
+        .cartesian_product(0..20)
+        .zip(0..)
+        .map(|((x, y), id)| {
+            let offset = if id % 2 == 0 { -30_000 } else { 10_000 };
+            Player {
+                location: I16Vec2::new(offset + x * 10, offset + y * 10),
+                id,
+            }
+        })
+        .collect();
+
+    let implicit = Bvh::build(&mut input, ());
+    let sah = Bvh::build_sah(&mut input, ());
+
+    assert!(!sah.is_implicit());
+    assert_le!(sah.cost(), implicit.cost());
+
+    let query = Aabb::new(I16Vec2::splat(-30_000), I16Vec2::splat(-29_900));
+
+    let ids = |bvh: &Bvh<Vec<EntityId>>| -> Vec<EntityId> {
+        bvh.get_in_slices(query)
+            .into_iter()
+            .flatten()
+            .copied()
+            .sorted()
+            .collect()
+    };
+
+    let expected: Vec<_> = input
+        .iter()
+        .filter(|player| query.contains_point(player.location))
+        .map(|player| player.id)
+        .sorted()
+        .collect();
+
+    assert!(!expected.is_empty());
+    assert_eq!(ids(&sah), expected);
+    assert_eq!(ids(&implicit), expected);
+}
+
+#[test]
+#[cfg(feature = "stats")]
+fn test_nodes_visited() {
+    let mut input = player_grid(4);
+
+    let bvh = Bvh::build(&mut input, ());
+    assert_eq!(bvh.nodes_visited(), 0);
+
+    let query = Aabb::new(I16Vec2::new(0, 0), I16Vec2::new(4, 4));
+    let found = bvh.get_in(query);
+    assert!(!found.is_empty());
+
+    let visited = bvh.nodes_visited();
+    assert!(visited > 0);
+
+    // a second query counts on top
+    bvh.get_in(query);
+    assert_eq!(bvh.nodes_visited(), visited * 2);
+
+    bvh.reset_nodes_visited();
+    assert_eq!(bvh.nodes_visited(), 0);
+
+    // iterating leaves is not a query
+    assert_eq!(bvh.iter_leaf_ranges().count(), input.len());
+    assert_eq!(bvh.nodes_visited(), 0);
+}